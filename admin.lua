@@ -32,6 +32,12 @@ if flag == 'init' then
   stylua_file:close()
   print('Created: ' .. name .. '.stylua.toml')
 
+  -- Generate pico.d.lua type definitions for the pico Lua module
+  local types_file = assert(io.open(name .. 'pico.d.lua', 'w'))
+  types_file:write(PICO_TYPES_TEMPLATE)
+  types_file:close()
+  print('Created: ' .. name .. 'pico.d.lua')
+
   print 'Would you like to generate any migrations (m), functions (f), both (a) or not (n)?'
   local input = io.read '*l'
   if input == 'm' or input == 'a' then
@@ -76,6 +82,14 @@ if flag == 'init' then
     pong_file:close()
     print('Created: ' .. name .. 'functions/pong.sql')
   end
+  if input == 'a' then
+    -- Test for the ping endpoint, which needs both its migration and function
+    os.execute('mkdir ' .. name .. 'tests/')
+    local test_file = assert(io.open(name .. 'tests/ping.lua', 'w'))
+    test_file:write(TEST_PING_TEMPLATE)
+    test_file:close()
+    print('Created: ' .. name .. 'tests/ping.lua')
+  end
 elseif flag == 'migrate' or flag == 'm' then
   local input = arg[2]
   
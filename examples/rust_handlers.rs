@@ -0,0 +1,41 @@
+//! Embeds pico in a Rust binary, answering a route with a Rust handler next to the Lua
+//! and SQL handlers declared in config.lua. Run it with the path of a pico project:
+//!
+//!     cargo run --example rust_handlers -- path/to/project
+//!
+//! and try `curl localhost:8080/reports/42`.
+use picos::{
+    PicoService,
+    http::http::{PicoResponse, ResponseCode},
+    route::route::Method,
+};
+use serde_json::json;
+
+fn main() -> std::io::Result<()> {
+    env_logger::init();
+
+    let root = std::env::args().nth(1).unwrap_or(".".to_string());
+    let mut pico = PicoService::builder()
+        .root(root)
+        .rust_handler("reports/:id", Method::GET, |ctx| {
+            let id: i64 = ctx.params["id"].parse().map_err(|_| {
+                PicoResponse::error(ResponseCode::BadRequest, "Report id must be a number")
+            })?;
+            // Runs in the request's transaction, as the request's RLS role
+            let rows = ctx
+                .query(
+                    "select $1::int8 as id, now()::text as generated_at",
+                    &[json!(id)],
+                )
+                .map_err(|e| PicoResponse::error(ResponseCode::InternalError, &e))?;
+            Ok(json!({ "report": rows.first(), "claims": ctx.claims }))
+        })
+        .rust_postprocess("reports/:id", Method::GET, |_ctx, mut body| {
+            body["source"] = json!("rust");
+            Ok(body)
+        })
+        .build()
+        .map_err(std::io::Error::other)?;
+
+    pico.start_http_server()
+}
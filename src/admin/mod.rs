@@ -1,7 +1,15 @@
 use log::error;
 use mlua::LuaSerdeExt;
-use std::{fs::File, io::Read};
-use picos::validate_pico_config;
+use std::{fs::File, io::Read, path::{Path, PathBuf}};
+use picos::{
+    jobs::jobs::{initialize_jobs, list_failed_jobs, retry_failed_jobs},
+    pico::pico::register_pico_module,
+    route::route::Method,
+    testing::testing::{
+        find_lua_tests, junit_report, run_lua_tests, tap_report, TestClient, TestRequest,
+    },
+    validate_pico_config, PicoConfig,
+};
 
 // Admin script and templates
 const ADMIN_SCRIPT: &str = include_str!("../../admin.lua");
@@ -9,6 +17,7 @@ const CONFIG_TEMPLATE: &str = include_str!("../../templates/config.lua");
 const STYLES_TEMPLATE: &str = include_str!("../../templates/styles.css");
 const AGENTS_TEMPLATE: &str = include_str!("../../templates/AGENTS.md");
 const STYLUA_TEMPLATE: &str = include_str!("../../templates/.stylua.toml");
+const PICO_TYPES_TEMPLATE: &str = include_str!("../../templates/pico.d.lua");
 
 // SQL Migration Templates
 const MIGRATION_PGCRYPTO_TEMPLATE: &str = include_str!("../../templates/migration_pgcrypto.sql");
@@ -21,17 +30,18 @@ const FUNCTION_REGISTER_USER_TEMPLATE: &str = include_str!("../../templates/func
 const FUNCTION_PONG_TEMPLATE: &str = include_str!("../../templates/function_pong.sql");
 const FUNCTION_TEMPLATE: &str = include_str!("../../templates/function_template.sql");
 
+// Test templates
+const TEST_PING_TEMPLATE: &str = include_str!("../../templates/test_ping.lua");
+
 /// Helper function to set a global variable in Lua with error handling
 fn set_lua_global(lua: &mlua::Lua, name: &str, value: &str) -> Result<(), mlua::Error> {
     lua.globals().set(name, value)
 }
 
-/// Validate a Pico configuration file
-pub fn validate_config(config_path: Option<String>) -> std::io::Result<()> {
-    let pico_config_path = config_path.unwrap_or("config.lua".to_string());
-    
+/// Read and parse a Pico configuration file, printing why it can't be
+fn load_config(pico_config_path: &str) -> std::io::Result<PicoConfig> {
     // Read the config file
-    let mut pico_config_file = match File::open(&pico_config_path) {
+    let mut pico_config_file = match File::open(pico_config_path) {
         Ok(file) => file,
         Err(e) => {
             eprintln!("Error: Failed to open config file '{}': {}", pico_config_path, e);
@@ -50,6 +60,10 @@ pub fn validate_config(config_path: Option<String>) -> std::io::Result<()> {
 
     // Parse the Lua config
     let lua = mlua::Lua::new();
+    if let Err(e) = register_pico_module(&lua) {
+        eprintln!("Error: Failed to register pico Lua module: {}", e);
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
+    }
     let pico_config_table = match lua.load(pico_config).eval() {
         Ok(table) => table,
         Err(e) => {
@@ -63,24 +77,7 @@ pub fn validate_config(config_path: Option<String>) -> std::io::Result<()> {
 
     // Validate the config using the lib.rs function
     match validate_pico_config(pico_config_table) {
-        Ok((port, db, routes, _route_tree, _crons)) => {
-            println!("✅ Configuration validation successful!");
-            println!("   Port: {}", port);
-            println!("   Database: {}", db);
-            println!("   Routes found: {}", routes.len());
-            
-            // List the routes
-            for (route_path, route) in routes.iter() {
-                println!("   - {} (methods: {})", 
-                    route_path, 
-                    route.definitions.keys()
-                        .map(|m| m.to_string())
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                );
-            }
-            Ok(())
-        }
+        Ok(config) => Ok(config),
         Err(validation_error) => {
             eprintln!("❌ Configuration validation failed:");
             eprintln!("   {}", validation_error);
@@ -89,6 +86,240 @@ pub fn validate_config(config_path: Option<String>) -> std::io::Result<()> {
     }
 }
 
+/// Validate a Pico configuration file
+pub fn validate_config(config_path: Option<String>) -> std::io::Result<()> {
+    let pico_config_path = config_path.unwrap_or("config.lua".to_string());
+    let config = load_config(&pico_config_path)?;
+
+    // Only embedding code can hand pico a database, picos needs DB
+    let db = match config.db {
+        Some(db) => db,
+        None => {
+            eprintln!("❌ Configuration validation failed:");
+            eprintln!("   DB is not set");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "DB is not set"));
+        }
+    };
+    println!("✅ Configuration validation successful!");
+    println!("   Port: {}", config.port);
+    println!("   Database: {}", db);
+    println!("   Routes found: {}", config.routes.len());
+    
+    // List the routes
+    for (route_path, route) in config.routes.iter() {
+        println!("   - {} (methods: {})", 
+            route_path, 
+            route.definitions.keys()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if let Some(crons) = &config.crons {
+        println!("   Crons found: {}", crons.jobs.len());
+        for job in &crons.jobs {
+            println!("   - {} (schedule: {} {})", job.name, job.schedule.expression, job.timezone);
+        }
+    }
+    if let Some(jobs) = &config.jobs {
+        println!("   Jobs found: {}", jobs.jobs.len());
+        for job in &jobs.jobs {
+            println!("   - {} (max attempts: {}, timeout: {:?})", job.name, job.max_attempts, job.timeout);
+        }
+    }
+    Ok(())
+}
+
+/// List the jobs in pico.failed_jobs, or move them back to the queue with retry, given
+/// their ids or all
+pub fn run_jobs(args: &[String]) -> std::io::Result<()> {
+    let usage = "Usage: picos jobs [retry ID...|retry all]";
+    let config = load_config("config.lua")?;
+    let db = match config.db {
+        Some(db) => db,
+        None => {
+            eprintln!("Error: DB is not set");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "DB is not set"));
+        }
+    };
+    let mut client = match postgres::Client::connect(&db, postgres::NoTls) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("❌ Failed to connect to the database: {}", e);
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+        }
+    };
+    if let Err(e) = initialize_jobs(&mut client) {
+        eprintln!("❌ {}", e);
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+    }
+
+    match args.first().map(|a| a.as_str()) {
+        None => {
+            let failed = list_failed_jobs(&mut client).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            if failed.is_empty() {
+                println!("✅ No failed jobs");
+                return Ok(());
+            }
+            println!("Failed jobs: {}", failed.len());
+            for job in &failed {
+                println!("   - {} {} (attempts: {}, failed at: {})", job.id, job.name, job.attempts, job.failed_at);
+                println!("     payload: {}", job.payload);
+                if let Some(error) = &job.error {
+                    println!("     error: {}", error);
+                }
+            }
+            println!("Retry them with picos jobs retry ID... or picos jobs retry all");
+            Ok(())
+        }
+        Some("retry") => {
+            let ids: Option<Vec<i64>> = match &args[1..] {
+                [] => {
+                    eprintln!("{}", usage);
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "missing job ids"));
+                }
+                [all] if all == "all" => None,
+                ids => match ids.iter().map(|id| id.parse()).collect() {
+                    Ok(ids) => Some(ids),
+                    Err(_) => {
+                        eprintln!("Error: Job ids are numbers");
+                        eprintln!("{}", usage);
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid job id"));
+                    }
+                },
+            };
+            match retry_failed_jobs(&mut client, ids.as_deref()) {
+                Ok(retried) => {
+                    println!("✅ Retried {} jobs", retried);
+                    Ok(())
+                }
+                Err(e) => {
+                    eprintln!("❌ {}", e);
+                    Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+                }
+            }
+        }
+        Some(_) => {
+            eprintln!("{}", usage);
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "unknown jobs command"))
+        }
+    }
+}
+
+/// Load the app in the current directory against the test database and run its Lua tests,
+/// from the files given or tests/*.lua. Given a method and path instead, send the app a
+/// single request without a socket. Nothing is written to the database.
+pub fn run_tests(args: &[String]) -> std::io::Result<()> {
+    let mut format = "tap";
+    let mut files = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--format" {
+            match args.next().map(|f| f.as_str()) {
+                Some(f) if f == "tap" || f == "junit" => format = f,
+                _ => {
+                    eprintln!("Error: --format expects tap or junit");
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "unknown test format"));
+                }
+            }
+        } else {
+            files.push(arg.clone());
+        }
+    }
+
+    let mut client = match TestClient::from_env() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("❌ Failed to load the app: {}", e);
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+        }
+    };
+
+    // picos test GET /ping sends a single request
+    let is_request = files.first().is_some_and(|f| !f.ends_with(".lua") && f.parse::<Method>().is_ok());
+    if is_request {
+        return send_test_request(&mut client, &files);
+    }
+
+    let files: Vec<PathBuf> = if files.is_empty() {
+        let tests_dir = Path::new("tests");
+        if !tests_dir.is_dir() {
+            println!("✅ App loaded against the test database");
+            return Ok(());
+        }
+        find_lua_tests(tests_dir).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+    } else {
+        files.iter().map(PathBuf::from).collect()
+    };
+
+    let results = match run_lua_tests(&mut client, &files) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("❌ Failed to run the tests: {}", e);
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
+        }
+    };
+    match format {
+        "junit" => print!("{}", junit_report(&results)),
+        _ => print!("{}", tap_report(&results)),
+    }
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+    if failed > 0 {
+        eprintln!("❌ {} of {} tests failed", failed, results.len());
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{} tests failed", failed),
+        ));
+    }
+    eprintln!("✅ {} tests passed", results.len());
+    Ok(())
+}
+
+/// Send one request to the app and print its response, failing on an error status
+fn send_test_request(client: &mut TestClient, args: &[String]) -> std::io::Result<()> {
+    let (method, path) = match args {
+        [method, path, ..] => (method, path),
+        _ => {
+            eprintln!("Usage: picos test [--format tap|junit] [FILE.lua...] or picos test METHOD PATH [JSON_BODY]");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "missing request path"));
+        }
+    };
+    let method: Method = match method.parse() {
+        Ok(m) => m,
+        Err(_) => {
+            eprintln!("Error: Unknown method {}", method);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "unknown method"));
+        }
+    };
+    let mut request = TestRequest::new(method, path);
+    if let Some(body) = args.get(2) {
+        match serde_json::from_str(body) {
+            Ok(json) => request = request.json(json),
+            Err(e) => {
+                eprintln!("Error: Request body is not JSON: {}", e);
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+            }
+        }
+    }
+
+    let response = client.send(request);
+    println!("{}", response.status());
+    for (name, values) in &response.response.headers {
+        for value in values {
+            println!("{}: {}", name, value);
+        }
+    }
+    println!();
+    println!("{}", response.text());
+    if response.status() >= 400 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("request failed with status {}", response.status()),
+        ));
+    }
+    Ok(())
+}
+
 /// Run the admin script with the provided command line arguments
 pub fn run_admin(args: Vec<String>) -> std::io::Result<()> {
     // Check for validate command first (handle in Rust)
@@ -101,6 +332,14 @@ pub fn run_admin(args: Vec<String>) -> std::io::Result<()> {
         return validate_config(config_path);
     }
 
+    if args.len() > 0 && args[0] == "test" {
+        return run_tests(&args[1..]);
+    }
+
+    if args.len() > 0 && args[0] == "jobs" {
+        return run_jobs(&args[1..]);
+    }
+
     let lua = mlua::Lua::new();
     
     // Convert arguments to Lua values
@@ -124,6 +363,7 @@ pub fn run_admin(args: Vec<String>) -> std::io::Result<()> {
         ("STYLES_TEMPLATE", STYLES_TEMPLATE),
         ("AGENTS_TEMPLATE", AGENTS_TEMPLATE),
         ("STYLUA_TEMPLATE", STYLUA_TEMPLATE),
+        ("PICO_TYPES_TEMPLATE", PICO_TYPES_TEMPLATE),
         ("MIGRATION_PGCRYPTO_TEMPLATE", MIGRATION_PGCRYPTO_TEMPLATE),
         ("MIGRATION_USERS_TABLE_TEMPLATE", MIGRATION_USERS_TABLE_TEMPLATE),
         ("MIGRATION_PING_COUNTER_TEMPLATE", MIGRATION_PING_COUNTER_TEMPLATE),
@@ -131,6 +371,7 @@ pub fn run_admin(args: Vec<String>) -> std::io::Result<()> {
         ("FUNCTION_REGISTER_USER_TEMPLATE", FUNCTION_REGISTER_USER_TEMPLATE),
         ("FUNCTION_PONG_TEMPLATE", FUNCTION_PONG_TEMPLATE),
         ("FUNCTION_TEMPLATE", FUNCTION_TEMPLATE),
+        ("TEST_PING_TEMPLATE", TEST_PING_TEMPLATE),
     ];
 
     for (name, template) in templates.iter() {
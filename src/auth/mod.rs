@@ -0,0 +1,829 @@
+pub mod auth {
+    use std::{
+        collections::HashMap,
+        str::FromStr,
+        time::{Duration, Instant},
+    };
+
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use chrono::Utc;
+    use hmac::{Hmac, Mac};
+    use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
+    use log::{debug, info, warn};
+    use mlua::{FromLua, Lua, LuaSerdeExt, Table, Value};
+    use postgres::Client;
+    use rand::RngCore;
+    use serde_json::Value as JsonValue;
+    use sha2::{Digest, Sha256};
+
+    use crate::{lua_duration, oidc::oidc::OidcConfig};
+
+    /// Authentication requirements declared with AUTH on a route or route group.
+    /// `AUTH = true` is shorthand for `AUTH = { REQUIRED = true }`.
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct AuthGuard {
+        pub required: bool,                     // Reject requests without valid JWT claims
+        pub roles: Vec<String>, // At least one of these must be in the role or roles claim
+        pub claims: HashMap<String, JsonValue>, // Claims that must be present with these values
+        pub redirect: Option<String>, // Where browsers asking for HTML are sent instead of an error
+    }
+
+    /// Why a request was turned away by an AuthGuard
+    #[derive(Debug, PartialEq)]
+    pub enum AuthFailure {
+        /// No valid JWT was provided, answered with a 401
+        Unauthenticated,
+        /// The JWT does not carry the required roles or claims, answered with a 403
+        Forbidden,
+    }
+
+    impl AuthGuard {
+        /// Checks JWT claims against the guard. Declaring ROLES or CLAIMS implies REQUIRED.
+        pub fn authorize(&self, jwt_claims: Option<&JsonValue>) -> Result<(), AuthFailure> {
+            let claims = match jwt_claims {
+                Some(c) => c,
+                None => {
+                    if self.required || !self.roles.is_empty() || !self.claims.is_empty() {
+                        return Err(AuthFailure::Unauthenticated);
+                    }
+                    return Ok(());
+                }
+            };
+
+            if !self.roles.is_empty() {
+                let mut granted: Vec<&str> = vec![];
+                if let Some(role) = claims.get("role").and_then(|r| r.as_str()) {
+                    granted.push(role);
+                }
+                if let Some(roles) = claims.get("roles").and_then(|r| r.as_array()) {
+                    granted.extend(roles.iter().filter_map(|r| r.as_str()));
+                }
+                if !self
+                    .roles
+                    .iter()
+                    .any(|role| granted.contains(&role.as_str()))
+                {
+                    return Err(AuthFailure::Forbidden);
+                }
+            }
+
+            for (name, expected) in &self.claims {
+                let matches = match claims.get(name) {
+                    // A list claim satisfies the requirement when it contains the value
+                    Some(JsonValue::Array(values)) if !expected.is_array() => {
+                        values.contains(expected)
+                    }
+                    Some(actual) => actual == expected,
+                    None => false,
+                };
+                if !matches {
+                    return Err(AuthFailure::Forbidden);
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    impl FromLua for AuthGuard {
+        fn from_lua(value: Value, lua: &Lua) -> mlua::Result<Self> {
+            match value {
+                Value::Boolean(required) => Ok(AuthGuard {
+                    required,
+                    ..Default::default()
+                }),
+                Value::Table(t) => {
+                    let required: Option<bool> = t.get("REQUIRED")?;
+                    let roles: Option<Vec<String>> = t.get("ROLES")?;
+                    let claims: Option<Table> = t.get("CLAIMS")?;
+                    let claims: HashMap<String, JsonValue> = match claims {
+                        Some(c) => lua.from_value(Value::Table(c))?,
+                        None => HashMap::new(),
+                    };
+                    let redirect: Option<String> = t.get("REDIRECT")?;
+                    Ok(AuthGuard {
+                        required: required.unwrap_or(true),
+                        roles: roles.unwrap_or_default(),
+                        claims,
+                        redirect,
+                    })
+                }
+                _ => Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "pico::auth::AuthGuard".to_string(),
+                    message: Some("expected AUTH to be a boolean or a table".to_string()),
+                }),
+            }
+        }
+    }
+
+    /// Authentication providers declared with the top level AUTH table in config.lua
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct AuthConfig {
+        pub oidc: Option<OidcConfig>, // Log in with an OpenID Connect provider
+        pub api_key: Option<ApiKeyConfig>, // Authenticate machine clients with API keys
+    }
+
+    impl FromLua for AuthConfig {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            match value {
+                Value::Table(t) => Ok(AuthConfig {
+                    oidc: t.get("OIDC")?,
+                    api_key: t.get("API_KEY")?,
+                }),
+                _ => Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "pico::auth::AuthConfig".to_string(),
+                    message: Some("expected AUTH to be a table".to_string()),
+                }),
+            }
+        }
+    }
+
+    /// API key settings declared with AUTH.API_KEY. Keys are hashed with SHA-256 and
+    /// checked by a SQL function that returns the claims for the key.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ApiKeyConfig {
+        pub header: String, // Request header carrying the key, matched case-insensitively
+        pub query: Option<String>, // Query parameter carrying the key, off unless set
+        pub sql_function_name: String,
+        pub cache_ttl: Duration, // How long lookups are remembered, 0 turns the cache off
+    }
+
+    impl FromLua for ApiKeyConfig {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            let conversion_error = |message: &str| mlua::Error::FromLuaConversionError {
+                from: "table",
+                to: "pico::auth::ApiKeyConfig".to_string(),
+                message: Some(message.to_string()),
+            };
+            let t = match value {
+                Value::Table(t) => t,
+                _ => return Err(conversion_error("expected AUTH.API_KEY to be a table")),
+            };
+
+            let sql_function_name: String = t
+                .get::<Option<String>>("SQL")?
+                .ok_or_else(|| conversion_error("AUTH.API_KEY requires SQL"))?;
+            let cache_ttl = match t.get::<Option<Value>>("CACHE_TTL")? {
+                Some(v) => lua_duration(&v).ok_or_else(|| {
+                    conversion_error(
+                        "invalid AUTH.API_KEY CACHE_TTL, expected seconds or a duration like '1m'",
+                    )
+                })?,
+                None => Duration::from_secs(60),
+            };
+            Ok(ApiKeyConfig {
+                header: t
+                    .get::<Option<String>>("HEADER")?
+                    .unwrap_or_else(|| "X-API-Key".to_string())
+                    .to_lowercase(),
+                query: t.get("QUERY")?,
+                sql_function_name,
+                cache_ttl,
+            })
+        }
+    }
+
+    /// Hashes an API key the way it is passed to the AUTH.API_KEY SQL function,
+    /// as hex encoded SHA-256
+    pub fn hash_api_key(key: &str) -> String {
+        Sha256::digest(key.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Remembers API key lookups for ApiKeyConfig.cache_ttl, so every request doesn't
+    /// hit the database. Unknown keys are remembered as None.
+    #[derive(Default)]
+    pub struct ApiKeyCache {
+        entries: HashMap<String, (Option<JsonValue>, Instant)>,
+    }
+
+    impl ApiKeyCache {
+        pub fn get(&self, key_hash: &str, ttl: Duration) -> Option<Option<JsonValue>> {
+            self.entries
+                .get(key_hash)
+                .filter(|(_, cached_at)| cached_at.elapsed() < ttl)
+                .map(|(claims, _)| claims.clone())
+        }
+
+        pub fn insert(&mut self, key_hash: String, claims: Option<JsonValue>, ttl: Duration) {
+            if ttl.is_zero() {
+                return;
+            }
+            self.entries
+                .retain(|_, (_, cached_at)| cached_at.elapsed() < ttl);
+            self.entries.insert(key_hash, (claims, Instant::now()));
+        }
+    }
+
+    /// Whether pico runs in development mode, enabled with PICO_ENV=dev
+    pub fn dev_mode() -> bool {
+        matches!(
+            std::env::var("PICO_ENV").as_deref(),
+            Ok("dev") | Ok("development")
+        )
+    }
+
+    /// JWT settings declared with the JWT table in config.lua
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct JwtConfig {
+        pub algorithm: Algorithm,
+        pub ttl: Option<Duration>, // Lifetime used to set exp, iat and nbf on issued tokens
+        pub issuer: Option<String>,
+        pub audience: Vec<String>,
+        pub private_key: Option<String>, // PEM file used to sign tokens for RS, PS, ES and EdDSA
+        pub public_key: Option<String>,  // PEM file used to verify tokens for RS, PS, ES and EdDSA
+        pub leeway: u64,                 // Seconds of clock skew tolerated on exp and nbf
+        pub session: Option<SessionConfig>, // Refresh token sessions, opted into with JWT.SESSION
+        pub cookie: CookieConfig,
+        pub token_field: Option<String>, // Response body field that issued tokens are added to
+    }
+
+    /// Cookie settings declared with JWT.COOKIE
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct CookieConfig {
+        pub name: String,
+        pub refresh_name: String, // Cookie holding the refresh token in session mode
+        pub csrf_name: String,    // Cookie holding the CSRF token for forms
+        pub path: String,
+        pub domain: Option<String>,
+        pub http_only: bool,
+        pub secure: bool,
+        pub same_site: Option<String>, // Strict, Lax or None
+        pub max_age: Option<Duration>, // Defaults to the JWT TTL
+    }
+
+    impl Default for CookieConfig {
+        fn default() -> Self {
+            CookieConfig {
+                name: "pico_jwt".to_string(),
+                refresh_name: "pico_refresh".to_string(),
+                csrf_name: "pico_csrf".to_string(),
+                path: "/".to_string(),
+                domain: None,
+                http_only: true,
+                secure: false,
+                same_site: Some("Lax".to_string()),
+                max_age: None,
+            }
+        }
+    }
+
+    impl CookieConfig {
+        /// Builds a Set-Cookie value with the configured attributes
+        pub fn set(&self, name: &str, value: &str, max_age: Option<Duration>) -> String {
+            let mut cookie = format!("{}={}; Path={}", name, value, self.path);
+            if let Some(domain) = &self.domain {
+                cookie.push_str(&format!("; Domain={}", domain));
+            }
+            if let Some(max_age) = max_age {
+                cookie.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+            }
+            if self.http_only {
+                cookie.push_str("; HttpOnly");
+            }
+            if self.secure {
+                cookie.push_str("; Secure");
+            }
+            if let Some(same_site) = &self.same_site {
+                cookie.push_str(&format!("; SameSite={}", same_site));
+            }
+            cookie
+        }
+
+        /// Builds the Set-Cookie value of the CSRF token, left readable by scripts so JS
+        /// clients can echo it in the X-CSRF-Token header
+        pub fn set_csrf(&self, value: &str) -> String {
+            CookieConfig {
+                http_only: false,
+                ..self.clone()
+            }
+            .set(&self.csrf_name, value, None)
+        }
+
+        /// Builds a Set-Cookie value that makes the browser drop the cookie
+        pub fn clear(&self, name: &str) -> String {
+            self.set(name, "", Some(Duration::ZERO))
+        }
+    }
+
+    impl FromLua for CookieConfig {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            let conversion_error = |message: String| mlua::Error::FromLuaConversionError {
+                from: "table",
+                to: "pico::auth::CookieConfig".to_string(),
+                message: Some(message),
+            };
+            let t = match value {
+                Value::Table(t) => t,
+                _ => {
+                    return Err(conversion_error(
+                        "expected JWT.COOKIE to be a table".to_string(),
+                    ));
+                }
+            };
+
+            let mut cookie = CookieConfig::default();
+            if let Some(name) = t.get::<Option<String>>("NAME")? {
+                cookie.name = name;
+            }
+            if let Some(refresh_name) = t.get::<Option<String>>("REFRESH_NAME")? {
+                cookie.refresh_name = refresh_name;
+            }
+            if let Some(csrf_name) = t.get::<Option<String>>("CSRF_NAME")? {
+                cookie.csrf_name = csrf_name;
+            }
+            if let Some(path) = t.get::<Option<String>>("PATH")? {
+                cookie.path = path;
+            }
+            cookie.domain = t.get("DOMAIN")?;
+            if let Some(http_only) = t.get::<Option<bool>>("HTTP_ONLY")? {
+                cookie.http_only = http_only;
+            }
+            if let Some(secure) = t.get::<Option<bool>>("SECURE")? {
+                cookie.secure = secure;
+            }
+            match t.get::<Option<Value>>("SAME_SITE")? {
+                Some(Value::Boolean(false)) => cookie.same_site = None,
+                Some(Value::String(same_site)) => {
+                    cookie.same_site = Some(match same_site.to_str()?.to_lowercase().as_str() {
+                        "strict" => "Strict".to_string(),
+                        "lax" => "Lax".to_string(),
+                        "none" if cookie.secure => "None".to_string(),
+                        "none" => {
+                            return Err(conversion_error(
+                                "JWT.COOKIE SAME_SITE = 'None' requires SECURE = true".to_string(),
+                            ));
+                        }
+                        other => {
+                            return Err(conversion_error(format!(
+                                "unknown JWT.COOKIE SAME_SITE {}, expected Strict | Lax | None",
+                                other
+                            )));
+                        }
+                    })
+                }
+                Some(other) => {
+                    return Err(conversion_error(format!(
+                        "invalid JWT.COOKIE SAME_SITE {}, expected Strict | Lax | None",
+                        other.type_name()
+                    )));
+                }
+                None => {}
+            }
+            if let Some(max_age) = t.get::<Option<Value>>("MAX_AGE")? {
+                cookie.max_age = Some(lua_duration(&max_age).ok_or_else(|| {
+                    conversion_error(
+                        "invalid JWT.COOKIE MAX_AGE, expected seconds or a duration like '7d'"
+                            .to_string(),
+                    )
+                })?);
+            }
+            Ok(cookie)
+        }
+    }
+
+    /// Session mode settings declared with JWT.SESSION. Access tokens live for the JWT TTL
+    /// and are renewed from a refresh token stored in pico.sessions.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct SessionConfig {
+        pub refresh_ttl: Duration, // Lifetime of a refresh token, extended on every rotation
+        pub refresh_before: Duration, // Access tokens this close to expiry are renewed
+    }
+
+    /// Default access token lifetime in session mode when JWT.TTL is not set
+    const DEFAULT_SESSION_ACCESS_TTL: Duration = Duration::from_secs(15 * 60);
+
+    impl Default for JwtConfig {
+        fn default() -> Self {
+            JwtConfig {
+                algorithm: Algorithm::HS256,
+                ttl: None,
+                issuer: None,
+                audience: vec![],
+                private_key: None,
+                public_key: None,
+                leeway: 60,
+                session: None,
+                cookie: CookieConfig::default(),
+                token_field: None,
+            }
+        }
+    }
+
+    impl FromLua for JwtConfig {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            let t = match value {
+                Value::Table(t) => t,
+                _ => {
+                    return Err(mlua::Error::FromLuaConversionError {
+                        from: value.type_name(),
+                        to: "pico::auth::JwtConfig".to_string(),
+                        message: Some("expected JWT to be a table".to_string()),
+                    });
+                }
+            };
+
+            let mut config = JwtConfig::default();
+            if let Some(algorithm) = t.get::<Option<String>>("ALGORITHM")? {
+                config.algorithm = Algorithm::from_str(&algorithm.to_uppercase()).map_err(|_| {
+                    mlua::Error::FromLuaConversionError {
+                        from: "String",
+                        to: "pico::auth::JwtConfig".to_string(),
+                        message: Some(format!(
+                            "unknown JWT ALGORITHM {}, expected one of HS256 | HS384 | HS512 | RS256 | RS384 | RS512 | PS256 | PS384 | PS512 | ES256 | ES384 | EdDSA",
+                            algorithm
+                        )),
+                    }
+                })?;
+            }
+            if let Some(ttl) = t.get::<Option<Value>>("TTL")? {
+                config.ttl = Some(lua_duration(&ttl).ok_or_else(|| {
+                    mlua::Error::FromLuaConversionError {
+                        from: ttl.type_name(),
+                        to: "pico::auth::JwtConfig".to_string(),
+                        message: Some(
+                            "invalid JWT TTL, expected seconds or a duration like '15m'"
+                                .to_string(),
+                        ),
+                    }
+                })?);
+            }
+            config.issuer = t.get("ISSUER")?;
+            config.audience = match t.get::<Option<Value>>("AUDIENCE")? {
+                Some(Value::Table(a)) => {
+                    a.sequence_values::<String>().collect::<mlua::Result<_>>()?
+                }
+                Some(a) => vec![String::from_lua(a, _lua)?],
+                None => vec![],
+            };
+            config.private_key = t.get("PRIVATE_KEY")?;
+            config.public_key = t.get("PUBLIC_KEY")?;
+            if let Some(leeway) = t.get::<Option<u64>>("LEEWAY")? {
+                config.leeway = leeway;
+            }
+            if let Some(session) = t.get::<Option<Table>>("SESSION")? {
+                let duration = |key: &str, default: Duration| -> mlua::Result<Duration> {
+                    match session.get::<Option<Value>>(key)? {
+                        Some(v) => lua_duration(&v).ok_or_else(|| {
+                            mlua::Error::FromLuaConversionError {
+                                from: v.type_name(),
+                                to: "pico::auth::SessionConfig".to_string(),
+                                message: Some(format!(
+                                    "invalid JWT.SESSION {}, expected seconds or a duration like '30d'",
+                                    key
+                                )),
+                            }
+                        }),
+                        None => Ok(default),
+                    }
+                };
+                config.session = Some(SessionConfig {
+                    refresh_ttl: duration("REFRESH_TTL", Duration::from_secs(30 * 24 * 60 * 60))?,
+                    refresh_before: duration("REFRESH_BEFORE", Duration::from_secs(5 * 60))?,
+                });
+                // Session mode only makes sense with short lived access tokens
+                if config.ttl.is_none() {
+                    config.ttl = Some(DEFAULT_SESSION_ACCESS_TTL);
+                }
+            }
+            if let Some(cookie) = t.get::<Option<CookieConfig>>("COOKIE")? {
+                config.cookie = cookie;
+            }
+            if config.cookie.max_age.is_none() {
+                config.cookie.max_age = config.ttl;
+            }
+            config.token_field = t.get("TOKEN_FIELD")?;
+            Ok(config)
+        }
+    }
+
+    /// Signing and verification keys built from a JwtConfig at startup
+    pub struct JwtKeys {
+        pub config: JwtConfig,
+        encoding_key: EncodingKey,
+        decoding_key: DecodingKey,
+        validation: Validation,
+        value_key: Vec<u8>, // Signs values pico hands to clients, like the OIDC login state
+    }
+
+    impl JwtKeys {
+        /// Loads the keys for the configured algorithm. HMAC algorithms use the given secret
+        /// or the PICO_SECRET_KEY environment variable, which is required outside of dev mode.
+        pub fn load(config: JwtConfig, secret: Option<String>) -> Result<Self, String> {
+            // Derived from the JWT secret or private key, so a signed value is never a valid
+            // JWT signature
+            let mut value_key = Sha256::new();
+            value_key.update(b"pico signed value ");
+            let (encoding_key, decoding_key) = match config.algorithm {
+                Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                    let secret = secret.or_else(|| std::env::var("PICO_SECRET_KEY").ok());
+                    let secret = match secret {
+                        Some(secret) if !secret.is_empty() => secret,
+                        _ if dev_mode() => {
+                            warn!(
+                                "PICO_SECRET_KEY is not set, signing JWTs with an insecure development secret"
+                            );
+                            "default_secret".to_string()
+                        }
+                        _ => {
+                            return Err(
+                                "PICO_SECRET_KEY must be set to sign JWTs. Set PICO_ENV=dev to use an insecure development secret."
+                                    .to_string(),
+                            );
+                        }
+                    };
+                    value_key.update(secret.as_bytes());
+                    (
+                        EncodingKey::from_secret(secret.as_ref()),
+                        DecodingKey::from_secret(secret.as_ref()),
+                    )
+                }
+                algorithm => {
+                    let read_pem = |path: &Option<String>, name: &str| -> Result<Vec<u8>, String> {
+                        let path = path.as_ref().ok_or(format!(
+                            "JWT ALGORITHM {:?} requires {} to be a path to a PEM file",
+                            algorithm, name
+                        ))?;
+                        std::fs::read(path)
+                            .map_err(|e| format!("failed to read JWT {} {}: {}", name, path, e))
+                    };
+                    let private_pem = read_pem(&config.private_key, "PRIVATE_KEY")?;
+                    let public_pem = read_pem(&config.public_key, "PUBLIC_KEY")?;
+                    value_key.update(&private_pem);
+                    let keys = match algorithm {
+                        Algorithm::ES256 | Algorithm::ES384 => {
+                            EncodingKey::from_ec_pem(&private_pem)
+                                .and_then(|e| Ok((e, DecodingKey::from_ec_pem(&public_pem)?)))
+                        }
+                        Algorithm::EdDSA => EncodingKey::from_ed_pem(&private_pem)
+                            .and_then(|e| Ok((e, DecodingKey::from_ed_pem(&public_pem)?))),
+                        _ => EncodingKey::from_rsa_pem(&private_pem)
+                            .and_then(|e| Ok((e, DecodingKey::from_rsa_pem(&public_pem)?))),
+                    };
+                    keys.map_err(|e| format!("invalid JWT key for {:?}: {}", algorithm, e))?
+                }
+            };
+
+            let mut validation = Validation::new(config.algorithm);
+            validation.leeway = config.leeway;
+            // Tokens only have to carry exp when pico sets it
+            if config.ttl.is_none() {
+                validation.required_spec_claims.remove("exp");
+            }
+            if let Some(issuer) = &config.issuer {
+                validation.set_issuer(&[issuer]);
+            }
+            if config.audience.is_empty() {
+                validation.validate_aud = false;
+            } else {
+                validation.set_audience(&config.audience);
+            }
+
+            Ok(JwtKeys {
+                config,
+                encoding_key,
+                decoding_key,
+                validation,
+                value_key: value_key.finalize().to_vec(),
+            })
+        }
+
+        /// Appends an HMAC to a value, so it can be checked when a client sends it back
+        pub fn sign_value(&self, value: &str) -> String {
+            let signature = self.value_mac(value).finalize().into_bytes();
+            format!("{}.{}", value, URL_SAFE_NO_PAD.encode(signature))
+        }
+
+        /// Returns the value signed by sign_value, or None when the signature doesn't match
+        pub fn verify_value<'a>(&self, signed: &'a str) -> Option<&'a str> {
+            let (value, signature) = signed.rsplit_once('.')?;
+            let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+            self.value_mac(value).verify_slice(&signature).ok()?;
+            Some(value)
+        }
+
+        fn value_mac(&self, value: &str) -> Hmac<Sha256> {
+            let mut mac = Hmac::<Sha256>::new_from_slice(&self.value_key)
+                .expect("HMAC accepts keys of any length");
+            mac.update(value.as_bytes());
+            mac
+        }
+
+        /// Verifies a token and returns its claims
+        pub fn decode(&self, token: &str) -> Option<JsonValue> {
+            match decode::<JsonValue>(token, &self.decoding_key, &self.validation) {
+                Ok(token_data) => Some(token_data.claims),
+                Err(e) => {
+                    debug!("JWT decode failed: {}", e);
+                    None
+                }
+            }
+        }
+
+        /// Signs claims returned by SETJWT, filling in exp, iat and nbf from the TTL and
+        /// iss and aud from the config unless the claims already set them
+        pub fn encode(&self, claims: JsonValue) -> Result<(String, JsonValue), String> {
+            let mut claims = match claims {
+                JsonValue::Object(c) => c,
+                other => return Err(format!("JWT claims must be a table, got {}", other)),
+            };
+
+            if let Some(ttl) = self.config.ttl {
+                let now = Utc::now().timestamp();
+                claims
+                    .entry("exp")
+                    .or_insert((now + ttl.as_secs() as i64).into());
+                claims.entry("iat").or_insert(now.into());
+                claims.entry("nbf").or_insert(now.into());
+            }
+            if let Some(issuer) = &self.config.issuer {
+                claims
+                    .entry("iss")
+                    .or_insert(JsonValue::String(issuer.clone()));
+            }
+            match self.config.audience.as_slice() {
+                [] => {}
+                [audience] => {
+                    claims
+                        .entry("aud")
+                        .or_insert(JsonValue::String(audience.clone()));
+                }
+                audience => {
+                    claims.entry("aud").or_insert(audience.into());
+                }
+            }
+
+            let claims = JsonValue::Object(claims);
+            match encode(
+                &Header::new(self.config.algorithm),
+                &claims,
+                &self.encoding_key,
+            ) {
+                Ok(jwt) => Ok((jwt, claims)),
+                Err(e) => Err(format!("error encoding JWT: {}", e)),
+            }
+        }
+    }
+
+    /// Creates the pico.sessions table that backs session mode
+    pub fn initialize_sessions(client: &mut Client) -> Result<(), String> {
+        client
+            .batch_execute(
+                "CREATE SCHEMA IF NOT EXISTS pico;
+                CREATE TABLE IF NOT EXISTS pico.sessions(
+                    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                    token_hash TEXT NOT NULL UNIQUE,
+                    previous_token_hash TEXT,
+                    claims JSONB NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                    rotated_at TIMESTAMPTZ,
+                    expires_at TIMESTAMPTZ NOT NULL,
+                    revoked_at TIMESTAMPTZ
+                );
+                CREATE INDEX IF NOT EXISTS sessions_previous_token_hash_idx
+                    ON pico.sessions(previous_token_hash);",
+            )
+            .map_err(|e| format!("error creating pico.sessions table: {}", e))
+    }
+
+    /// Generates 32 random bytes encoded as base64url
+    fn random_token() -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// Generates a random refresh token and the hash stored for it in pico.sessions
+    fn new_refresh_token() -> (String, String) {
+        let token = random_token();
+        let hash = hash_refresh_token(&token);
+        (token, hash)
+    }
+
+    /// Generates a random token for the CSRF cookie
+    pub fn new_csrf_token() -> String {
+        random_token()
+    }
+
+    /// Compares the CSRF token sent with a request to the one in its cookie, in constant time
+    pub fn csrf_token_matches(expected: &str, provided: &str) -> bool {
+        expected.len() == provided.len()
+            && expected
+                .bytes()
+                .zip(provided.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    fn hash_refresh_token(token: &str) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+    }
+
+    /// Stores a new session for the claims returned by SETJWT and returns the session id
+    /// and its refresh token
+    pub fn create_session(
+        client: &mut Client,
+        claims: &JsonValue,
+        session: &SessionConfig,
+    ) -> Result<(String, String), String> {
+        let (token, hash) = new_refresh_token();
+        let row = client
+            .query_one(
+                "INSERT INTO pico.sessions(token_hash, claims, expires_at)
+                VALUES ($1, $2, now() + make_interval(secs => $3))
+                RETURNING id::text",
+                &[&hash, claims, &(session.refresh_ttl.as_secs() as f64)],
+            )
+            .map_err(|e| format!("error creating session: {}", e))?;
+        Ok((row.get(0), token))
+    }
+
+    /// How long a rotated refresh token is still accepted, for requests that were sent
+    /// with it concurrently, like several tabs renewing an expired access token at once
+    pub const REFRESH_REUSE_GRACE: Duration = Duration::from_secs(30);
+
+    /// Exchanges a refresh token for a new one, returning the session id, the claims
+    /// stored for the session and the new refresh token. A token rotated away less than
+    /// REFRESH_REUSE_GRACE ago returns the session without a new refresh token. Presenting
+    /// it later revokes the whole session, since it has likely been stolen.
+    pub fn rotate_session(
+        client: &mut Client,
+        refresh_token: &str,
+        session: &SessionConfig,
+    ) -> Result<Option<(String, JsonValue, Option<String>)>, String> {
+        let hash = hash_refresh_token(refresh_token);
+        let (token, new_hash) = new_refresh_token();
+        let rotated = client
+            .query_opt(
+                "UPDATE pico.sessions
+                SET token_hash = $2, previous_token_hash = token_hash, rotated_at = now(),
+                    expires_at = now() + make_interval(secs => $3)
+                WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > now()
+                RETURNING id::text, claims",
+                &[&hash, &new_hash, &(session.refresh_ttl.as_secs() as f64)],
+            )
+            .map_err(|e| format!("error rotating session: {}", e))?;
+
+        if let Some(row) = rotated {
+            return Ok(Some((row.get(0), row.get(1), Some(token))));
+        }
+
+        let concurrent = client
+            .query_opt(
+                "SELECT id::text, claims FROM pico.sessions
+                WHERE previous_token_hash = $1 AND revoked_at IS NULL AND expires_at > now()
+                    AND rotated_at > now() - make_interval(secs => $2)",
+                &[&hash, &(REFRESH_REUSE_GRACE.as_secs() as f64)],
+            )
+            .map_err(|e| format!("error checking refresh token reuse: {}", e))?;
+        if let Some(row) = concurrent {
+            return Ok(Some((row.get(0), row.get(1), None)));
+        }
+
+        let reused = client
+            .execute(
+                "UPDATE pico.sessions SET revoked_at = now()
+                WHERE previous_token_hash = $1 AND revoked_at IS NULL",
+                &[&hash],
+            )
+            .map_err(|e| format!("error checking refresh token reuse: {}", e))?;
+        if reused > 0 {
+            warn!("Rotated refresh token was presented again, revoking its session");
+        }
+        Ok(None)
+    }
+
+    /// Whether a session exists and has not been revoked or expired
+    pub fn session_active(client: &mut Client, session_id: &str) -> Result<bool, String> {
+        client
+            .query_opt(
+                "SELECT 1 FROM pico.sessions
+                WHERE id::text = $1 AND revoked_at IS NULL AND expires_at > now()",
+                &[&session_id],
+            )
+            .map(|row| row.is_some())
+            .map_err(|e| format!("error checking session: {}", e))
+    }
+
+    /// Revokes a session by id or by one of its refresh tokens
+    pub fn revoke_session(
+        client: &mut Client,
+        session_id: Option<&str>,
+        refresh_token: Option<&str>,
+    ) -> Result<(), String> {
+        let hash = refresh_token.map(hash_refresh_token);
+        let revoked = client
+            .execute(
+                "UPDATE pico.sessions SET revoked_at = now()
+                WHERE revoked_at IS NULL AND (id::text = $1 OR token_hash = $2)",
+                &[&session_id, &hash],
+            )
+            .map_err(|e| format!("error revoking session: {}", e))?;
+        info!("Revoked {} session(s)", revoked);
+        Ok(())
+    }
+}
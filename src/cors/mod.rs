@@ -0,0 +1,142 @@
+pub mod cors {
+    use std::time::Duration;
+
+    use mlua::{FromLua, Lua, Value};
+
+    use crate::lua_duration;
+
+    /// Cross-origin settings declared with CORS globally, on a group or on a route
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct CorsConfig {
+        pub origins: Vec<String>, // Origins allowed to call pico, '*' allows any origin
+        pub methods: Vec<String>,
+        pub headers: Vec<String>, // Request headers browsers may send, beyond the safelisted ones
+        pub credentials: bool,    // Whether browsers may send cookies and read the response
+        pub max_age: Option<Duration>, // How long browsers can cache a preflight
+    }
+
+    impl FromLua for CorsConfig {
+        fn from_lua(value: Value, lua: &Lua) -> mlua::Result<Self> {
+            let conversion_error = |message: &str| mlua::Error::FromLuaConversionError {
+                from: "table",
+                to: "pico::cors::CorsConfig".to_string(),
+                message: Some(message.to_string()),
+            };
+            let t = match value {
+                Value::Table(t) => t,
+                _ => return Err(conversion_error("expected CORS to be a table")),
+            };
+
+            // A single value can be given as a string instead of a list
+            let list = |key: &str| -> mlua::Result<Option<Vec<String>>> {
+                match t.get::<Value>(key)? {
+                    Value::Nil => Ok(None),
+                    Value::String(s) => Ok(Some(vec![s.to_str()?.to_string()])),
+                    v => Vec::<String>::from_lua(v, lua).map(Some),
+                }
+            };
+
+            let origins: Vec<String> = list("ORIGINS")?
+                .filter(|origins| !origins.is_empty())
+                .ok_or_else(|| conversion_error("CORS requires ORIGINS"))?
+                .into_iter()
+                .map(|origin| origin.trim_end_matches('/').to_string())
+                .collect();
+            let credentials = t.get::<Option<bool>>("CREDENTIALS")?.unwrap_or(false);
+            if credentials && origins.iter().any(|origin| origin == "*") {
+                // Browsers refuse credentialed responses that allow any origin
+                return Err(conversion_error(
+                    "CORS CREDENTIALS can't be used with ORIGINS '*', list the origins instead",
+                ));
+            }
+            let methods = list("METHODS")?
+                .unwrap_or_else(|| {
+                    vec![
+                        "GET".to_string(),
+                        "POST".to_string(),
+                        "PUT".to_string(),
+                        "DELETE".to_string(),
+                    ]
+                })
+                .into_iter()
+                .map(|method| method.to_uppercase())
+                .collect();
+            // Cookie clients send X-CSRF-Token with unsafe requests, see docs/auth.md
+            let headers = list("HEADERS")?.unwrap_or_else(|| {
+                vec![
+                    "Content-Type".to_string(),
+                    "Authorization".to_string(),
+                    "X-CSRF-Token".to_string(),
+                ]
+            });
+            let max_age = match t.get::<Option<Value>>("MAX_AGE")? {
+                Some(v) => Some(lua_duration(&v).ok_or_else(|| {
+                    conversion_error(
+                        "invalid CORS MAX_AGE, expected seconds or a duration like '10m'",
+                    )
+                })?),
+                None => None,
+            };
+
+            Ok(CorsConfig {
+                origins,
+                methods,
+                headers,
+                credentials,
+                max_age,
+            })
+        }
+    }
+
+    impl CorsConfig {
+        pub fn allows_origin(&self, origin: &str) -> bool {
+            self.origins
+                .iter()
+                .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+        }
+
+        /// Headers letting the origin read a response. The origin must be allowed.
+        pub fn response_headers(&self, origin: &str) -> Vec<(String, String)> {
+            let mut headers = vec![];
+            if self.origins.iter().any(|allowed| allowed == "*") {
+                headers.push(("Access-Control-Allow-Origin".to_string(), "*".to_string()));
+            } else {
+                headers.push((
+                    "Access-Control-Allow-Origin".to_string(),
+                    origin.to_string(),
+                ));
+                // Caches must not hand a response allowing one origin to another
+                headers.push(("Vary".to_string(), "Origin".to_string()));
+            }
+            if self.credentials {
+                headers.push((
+                    "Access-Control-Allow-Credentials".to_string(),
+                    "true".to_string(),
+                ));
+            }
+            headers
+        }
+
+        /// Headers answering a preflight from the origin. The origin must be allowed.
+        pub fn preflight_headers(&self, origin: &str) -> Vec<(String, String)> {
+            let mut headers = self.response_headers(origin);
+            headers.push((
+                "Access-Control-Allow-Methods".to_string(),
+                self.methods.join(", "),
+            ));
+            if !self.headers.is_empty() {
+                headers.push((
+                    "Access-Control-Allow-Headers".to_string(),
+                    self.headers.join(", "),
+                ));
+            }
+            if let Some(max_age) = self.max_age {
+                headers.push((
+                    "Access-Control-Max-Age".to_string(),
+                    max_age.as_secs().to_string(),
+                ));
+            }
+            headers
+        }
+    }
+}
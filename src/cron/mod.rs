@@ -1,6 +1,706 @@
 pub mod cron {
-    use serde::Deserialize;
+    use std::{
+        collections::{HashMap, VecDeque},
+        thread,
+        time::{Duration, Instant},
+    };
 
-    #[derive(Deserialize, Debug, PartialEq)]
-    pub struct Crons {}
+    use chrono::{Datelike, NaiveDate, NaiveDateTime, TimeDelta, Timelike};
+    use log::{debug, error, info};
+    use mlua::{FromLua, Function, Lua, LuaSerdeExt, Table, Value};
+    use postgres::Client;
+    use serde_json::Value as JsonValue;
+
+    use crate::{
+        extract_lua_error_message, lua_duration,
+        pico::pico::{SqlContext, with_request_sql},
+        sandbox::sandbox::{HandlerTimer, install_timeout_hook},
+        sql::sql::SQL,
+    };
+
+    const MONTH_NAMES: [&str; 12] = [
+        "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+    ];
+    const WEEKDAY_NAMES: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+    /// Longest the scheduler sleeps before reading the clock again, so it follows changes
+    /// of UTC offset and of the system clock
+    const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+    /// Wait before checking a job again after an error that left the connection usable
+    const RETRY_AFTER_ERROR: Duration = Duration::from_secs(10);
+
+    /// How late a run can start before CATCH_UP = 'skip' counts it as missed
+    const ON_TIME: Duration = Duration::from_secs(60);
+
+    /// Most missed runs CATCH_UP = 'all' runs, the most recent ones
+    const MAX_CATCH_UP: usize = 100;
+
+    /// Jobs declared with CRONS, run on their schedules by a background thread
+    #[derive(Debug, PartialEq)]
+    pub struct Crons {
+        pub jobs: Vec<CronJob>, // Sorted by name
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct CronJob {
+        pub name: String,
+        pub schedule: CronSchedule,
+        pub timezone: String, // Time zone the schedule is read in, UTC by default
+        pub sql_function_name: Option<String>,
+        pub pre_process: Option<Function>,
+        pub post_process: Option<Function>,
+        pub timeout: Option<Duration>, // Longest a run can take before it's rolled back
+        pub catch_up: CatchUp,
+    }
+
+    /// What happens to runs missed while no instance could run the job, because they
+    /// were down, busy with other jobs or still running the job
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum CatchUp {
+        /// Missed runs are dropped, the job next runs at its next scheduled time
+        Skip,
+        /// The job runs once as soon as it can, however many runs it missed
+        Once,
+        /// Every missed run is run, oldest first, up to the last MAX_CATCH_UP
+        All,
+    }
+
+    /// The runs of a job that came due since the last one claimed
+    #[derive(Debug, PartialEq)]
+    pub(crate) struct DueRuns {
+        pub runs: Vec<NaiveDateTime>,      // The runs to start, oldest first
+        pub latest: Option<NaiveDateTime>, // The latest run due, whether it runs or not
+        pub skipped: bool,                 // Whether runs that came due were left out
+    }
+
+    impl CatchUp {
+        /// Picks the runs to start out of those due after `last`, up to `now`
+        pub(crate) fn select_runs(
+            self,
+            schedule: &CronSchedule,
+            last: NaiveDateTime,
+            now: NaiveDateTime,
+        ) -> DueRuns {
+            let limit = match self {
+                CatchUp::All => MAX_CATCH_UP,
+                CatchUp::Once | CatchUp::Skip => 1,
+            };
+            let (runs, skipped) = latest_runs(schedule, last, now, limit);
+            let latest = runs.last().copied();
+            let late =
+                latest.is_some_and(|latest| (now - latest).to_std().unwrap_or_default() > ON_TIME);
+            match self {
+                CatchUp::Skip if late => DueRuns {
+                    runs: vec![],
+                    latest,
+                    skipped: true,
+                },
+                _ => DueRuns {
+                    runs,
+                    latest,
+                    skipped,
+                },
+            }
+        }
+    }
+
+    /// The latest runs of a schedule after `last`, up to `now`, at most `limit` of them,
+    /// and whether earlier ones were left out. Rather than stepping through every run since
+    /// `last`, which takes long after an outage for a job running every second, it looks
+    /// for them in a window ending at now that doubles until it holds enough.
+    fn latest_runs(
+        schedule: &CronSchedule,
+        last: NaiveDateTime,
+        now: NaiveDateTime,
+        limit: usize,
+    ) -> (Vec<NaiveDateTime>, bool) {
+        let mut window = TimeDelta::minutes(1);
+        loop {
+            let from = now
+                .checked_sub_signed(window)
+                .filter(|from| *from > last)
+                .unwrap_or(last);
+            let mut runs = VecDeque::new();
+            let mut dropped = false;
+            let mut next = schedule.next_after(from);
+            while let Some(scheduled_at) = next.filter(|next| *next <= now) {
+                if runs.len() == limit {
+                    runs.pop_front();
+                    dropped = true;
+                }
+                runs.push_back(scheduled_at);
+                next = schedule.next_after(scheduled_at);
+            }
+            if from == last {
+                return (runs.into(), dropped);
+            }
+            if runs.len() == limit {
+                let earlier = schedule.next_after(last).is_some_and(|first| first <= from);
+                return (runs.into(), dropped || earlier);
+            }
+            window = window * 2;
+        }
+    }
+
+    /// A cron expression, with 5 fields (minute hour day month weekday) or 6 when it starts
+    /// with seconds. Each field is a bit set of the values it matches.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct CronSchedule {
+        pub expression: String,
+        seconds: u64,
+        minutes: u64,
+        hours: u64,
+        days: u64,
+        months: u64,
+        weekdays: u64,     // Sunday is 0
+        any_day: bool,     // The day of month field is *, so only the weekday restricts days
+        any_weekday: bool, // The weekday field is *, so only the day of month restricts days
+    }
+
+    impl FromLua for Crons {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            let conversion_error = |message: String| mlua::Error::FromLuaConversionError {
+                from: "table",
+                to: "pico::cron::Crons".to_string(),
+                message: Some(message),
+            };
+            let t = match value {
+                Value::Table(t) => t,
+                _ => return Err(conversion_error("expected CRONS to be a table".to_string())),
+            };
+
+            let mut jobs = vec![];
+            for pair in t.pairs::<String, Table>() {
+                let (name, job) = pair.map_err(|e| {
+                    conversion_error(format!("expected CRONS to map job names to tables, {}", e))
+                })?;
+                let schedule = match job.get::<Option<String>>("SCHEDULE")? {
+                    Some(expression) => CronSchedule::parse(&expression)
+                        .map_err(|e| conversion_error(format!("{} SCHEDULE {}", name, e)))?,
+                    None => return Err(conversion_error(format!("{} requires a SCHEDULE", name))),
+                };
+                let timezone = job
+                    .get::<Option<String>>("TIMEZONE")?
+                    .unwrap_or("UTC".to_string());
+                let sql_function_name: Option<String> = job.get("SQL")?;
+                let pre_process: Option<Function> = job.get("PREPROCESS")?;
+                let post_process: Option<Function> = job.get("POSTPROCESS")?;
+                let timeout = match job.get::<Option<Value>>("TIMEOUT")? {
+                    Some(v) => {
+                        Some(lua_duration(&v).filter(|d| !d.is_zero()).ok_or_else(|| {
+                            conversion_error(format!(
+                                "invalid {} TIMEOUT, expected seconds or a duration like '5m'",
+                                name
+                            ))
+                        })?)
+                    }
+                    None => None,
+                };
+                let catch_up = match job.get::<Option<String>>("CATCH_UP")?.as_deref() {
+                    None | Some("skip") => CatchUp::Skip,
+                    Some("once") => CatchUp::Once,
+                    Some("all") => CatchUp::All,
+                    Some(other) => {
+                        return Err(conversion_error(format!(
+                            "unknown {} CATCH_UP {}, expected 'skip', 'once' or 'all'",
+                            name, other
+                        )));
+                    }
+                };
+                if sql_function_name.is_none() && pre_process.is_none() && post_process.is_none() {
+                    return Err(conversion_error(format!(
+                        "{} has nothing to run, expected SQL, PREPROCESS or POSTPROCESS",
+                        name
+                    )));
+                }
+                jobs.push(CronJob {
+                    name,
+                    schedule,
+                    timezone,
+                    sql_function_name,
+                    pre_process,
+                    post_process,
+                    timeout,
+                    catch_up,
+                });
+            }
+            jobs.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(Crons { jobs })
+        }
+    }
+
+    impl CronSchedule {
+        pub fn parse(expression: &str) -> Result<CronSchedule, String> {
+            let expanded = match expression.trim() {
+                "@yearly" | "@annually" => "0 0 1 1 *",
+                "@monthly" => "0 0 1 * *",
+                "@weekly" => "0 0 * * 0",
+                "@daily" | "@midnight" => "0 0 * * *",
+                "@hourly" => "0 * * * *",
+                other => other,
+            };
+            let fields: Vec<&str> = expanded.split_whitespace().collect();
+            let (seconds, fields) = match fields.len() {
+                5 => ("0", &fields[..]),
+                6 => (fields[0], &fields[1..]),
+                n => {
+                    return Err(format!(
+                        "'{}' has {} fields, expected 5 (minute hour day month weekday) or 6 starting with seconds",
+                        expression, n
+                    ));
+                }
+            };
+            let field = |name: &str, text: &str, min: u32, max: u32, names: &[&str]| {
+                parse_field(text, min, max, names)
+                    .map_err(|e| format!("'{}' has an invalid {} field, {}", expression, name, e))
+            };
+            let mut weekdays = field("weekday", fields[4], 0, 7, &WEEKDAY_NAMES)?;
+            // 7 is Sunday too
+            if weekdays & (1 << 7) != 0 {
+                weekdays = (weekdays | 1) & !(1 << 7);
+            }
+            let schedule = CronSchedule {
+                expression: expression.to_string(),
+                seconds: field("seconds", seconds, 0, 59, &[])?,
+                minutes: field("minute", fields[0], 0, 59, &[])?,
+                hours: field("hour", fields[1], 0, 23, &[])?,
+                days: field("day", fields[2], 1, 31, &[])?,
+                months: field("month", fields[3], 1, 12, &MONTH_NAMES)?,
+                weekdays,
+                any_day: matches!(fields[2], "*" | "?"),
+                any_weekday: matches!(fields[4], "*" | "?"),
+            };
+            // Like 0 0 31 2 *, which matches no date. Eight years always include a leap day.
+            let start = NaiveDate::from_ymd_opt(2000, 1, 1)
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+                .unwrap_or_default();
+            if schedule.next_after(start).is_none() {
+                return Err(format!("'{}' never matches a date", expression));
+            }
+            Ok(schedule)
+        }
+
+        /// The first time matching the schedule strictly after the given one, both in the
+        /// schedule's time zone
+        pub fn next_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+            let mut t = time.with_nanosecond(0)? + TimeDelta::seconds(1);
+            let last_year = time.year() + 8;
+            while t.year() <= last_year {
+                if !has(self.months, t.month()) {
+                    let (year, month) = match t.month() {
+                        12 => (t.year() + 1, 1),
+                        month => (t.year(), month + 1),
+                    };
+                    t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+                } else if !self.day_matches(t.date()) {
+                    t = t.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+                } else if !has(self.hours, t.hour()) {
+                    t = t.date().and_hms_opt(t.hour(), 0, 0)? + TimeDelta::hours(1);
+                } else if !has(self.minutes, t.minute()) {
+                    t = t.date().and_hms_opt(t.hour(), t.minute(), 0)? + TimeDelta::minutes(1);
+                } else if !has(self.seconds, t.second()) {
+                    t += TimeDelta::seconds(1);
+                } else {
+                    return Some(t);
+                }
+            }
+            None
+        }
+
+        /// When both the day of month and the weekday are restricted, a date matching
+        /// either one matches, like in crontab
+        fn day_matches(&self, date: NaiveDate) -> bool {
+            let day = has(self.days, date.day());
+            let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+            match self.any_day || self.any_weekday {
+                true => day && weekday,
+                false => day || weekday,
+            }
+        }
+    }
+
+    fn has(field: u64, value: u32) -> bool {
+        field & (1 << value) != 0
+    }
+
+    /// Parses a field like `*/15`, `1-5`, `MON-FRI` or `0,30` to a bit set of its values
+    fn parse_field(text: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+        let value = |text: &str| -> Result<u32, String> {
+            let value = match names.iter().position(|n| n.eq_ignore_ascii_case(text)) {
+                Some(position) => position as u32 + min,
+                None => text
+                    .parse::<u32>()
+                    .map_err(|_| format!("'{}' is not a number", text))?,
+            };
+            match value < min || value > max {
+                true => Err(format!("{} is not between {} and {}", value, min, max)),
+                false => Ok(value),
+            }
+        };
+
+        let mut field = 0;
+        for item in text.split(',') {
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => match step.parse::<u32>() {
+                    Ok(step) if step > 0 => (range, Some(step)),
+                    _ => return Err(format!("'{}' is not a valid step", step)),
+                },
+                None => (item, None),
+            };
+            let (start, end) = match range {
+                "*" | "?" => (min, max),
+                range => match range.split_once('-') {
+                    Some((start, end)) => (value(start)?, value(end)?),
+                    // 5/15 starts at 5 and steps to the end of the range
+                    None if step.is_some() => (value(range)?, max),
+                    None => (value(range)?, value(range)?),
+                },
+            };
+            if start > end {
+                return Err(format!("{} starts after it ends", range));
+            }
+            for v in (start..=end).step_by(step.unwrap_or(1) as usize) {
+                field |= 1 << v;
+            }
+        }
+        Ok(field)
+    }
+
+    /// Creates the pico.cron_jobs table runs are claimed from, and the pico.cron_runs table
+    /// every run of a job is recorded in
+    pub fn initialize_crons(client: &mut Client) -> Result<(), String> {
+        client
+            .batch_execute(
+                "CREATE SCHEMA IF NOT EXISTS pico;
+                CREATE TABLE IF NOT EXISTS pico.cron_jobs(
+                    name TEXT PRIMARY KEY,
+                    last_scheduled_at TIMESTAMPTZ NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS pico.cron_runs(
+                    id BIGSERIAL PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    scheduled_at TIMESTAMPTZ NOT NULL,
+                    started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                    finished_at TIMESTAMPTZ,
+                    duration_ms BIGINT,
+                    status TEXT NOT NULL,
+                    error TEXT,
+                    result JSONB
+                );
+                CREATE INDEX IF NOT EXISTS cron_runs_name_idx
+                    ON pico.cron_runs(name, scheduled_at DESC);",
+            )
+            .map_err(|e| {
+                format!(
+                    "error creating pico.cron_jobs and pico.cron_runs tables: {}",
+                    e
+                )
+            })
+    }
+
+    /// Checks a time zone is one Postgres knows, like 'Europe/Oslo' or 'UTC'
+    pub fn check_timezone(client: &mut Client, timezone: &str) -> Result<(), String> {
+        local_now(client, timezone).map(|_| ())
+    }
+
+    /// The current wall clock time in a time zone. Postgres holds the time zone database,
+    /// so pico doesn't need one of its own.
+    fn local_now(client: &mut Client, timezone: &str) -> Result<NaiveDateTime, String> {
+        client
+            .query_one("SELECT (now() AT TIME ZONE $1)::timestamp", &[&timezone])
+            .map(|row| row.get(0))
+            .map_err(|e| format!("error reading the time in {}: {}", timezone, e))
+    }
+
+    /// Runs the jobs on their schedules, one at a time, so a long run delays the jobs due
+    /// during it until it ends. Every instance of the app runs a scheduler, and they share
+    /// the jobs through pico.cron_jobs, so each scheduled run happens once. Errors of a job
+    /// are logged and the job is checked again later. Returns when the connection is lost.
+    pub fn run_scheduler(lua: &Lua, sql: &mut SQL, crons: &Crons) -> Result<(), String> {
+        for job in &crons.jobs {
+            // A new job starts from now, rather than catching up on its whole history
+            sql.connection
+                .execute(
+                    "INSERT INTO pico.cron_jobs(name, last_scheduled_at) VALUES ($1, now())
+                    ON CONFLICT (name) DO NOTHING",
+                    &[&job.name],
+                )
+                .map_err(|e| format!("error registering cron {}: {}", job.name, e))?;
+        }
+        if crons.jobs.iter().any(|job| job.timeout.is_some()) {
+            install_timeout_hook(lua).map_err(|e| format!("error setting TIMEOUT: {}", e))?;
+        }
+
+        // None when the job is due for a check, like on start, to catch up on runs missed
+        // while no instance was up
+        let mut next_runs: Vec<Option<NaiveDateTime>> = vec![None; crons.jobs.len()];
+        loop {
+            let mut sleep = MAX_SLEEP;
+            for (job, next_run) in crons.jobs.iter().zip(next_runs.iter_mut()) {
+                match check_job(lua, sql, job, next_run) {
+                    Ok(wait) => sleep = sleep.min(wait),
+                    Err(e) if sql.connection.is_closed() => return Err(e),
+                    Err(e) => {
+                        error!("{}", e);
+                        *next_run = None;
+                        sleep = sleep.min(RETRY_AFTER_ERROR);
+                    }
+                }
+            }
+            debug!("CRONS sleeping for {:?}", sleep);
+            thread::sleep(sleep);
+        }
+    }
+
+    /// Runs the job if it's due, and returns how long to wait before checking it again
+    fn check_job(
+        lua: &Lua,
+        sql: &mut SQL,
+        job: &CronJob,
+        next_run: &mut Option<NaiveDateTime>,
+    ) -> Result<Duration, String> {
+        let now = local_now(&mut sql.connection, &job.timezone)?;
+        if next_run.is_none_or(|next| now >= next) {
+            // After a run, check again right away for runs missed during it
+            *next_run = match run_due(lua, sql, job, now)? {
+                true => None,
+                false => job.schedule.next_after(now),
+            };
+        }
+        Ok(match *next_run {
+            Some(next) => (next - now).to_std().unwrap_or(Duration::ZERO),
+            None => Duration::ZERO,
+        })
+    }
+
+    /// Claims the runs of the job that are due and runs them, unless a run of the job is
+    /// still going on, here or on another instance. Whether the job ran.
+    fn run_due(
+        lua: &Lua,
+        sql: &mut SQL,
+        job: &CronJob,
+        now: NaiveDateTime,
+    ) -> Result<bool, String> {
+        // Held for the whole run, and released by Postgres if the instance dies
+        let locked: bool = sql
+            .connection
+            .query_one(
+                "SELECT pg_try_advisory_lock(hashtext('pico.cron_jobs'), hashtext($1))",
+                &[&job.name],
+            )
+            .map(|row| row.get(0))
+            .map_err(|e| format!("error locking cron {}: {}", job.name, e))?;
+        if !locked {
+            debug!("Cron {} is already running", job.name);
+            return Ok(false);
+        }
+
+        let runs = claim_runs(&mut sql.connection, job, now);
+        if let Ok(runs) = &runs {
+            for scheduled_at in runs {
+                run_job(lua, sql, job, *scheduled_at);
+            }
+        }
+        sql.connection
+            .execute(
+                "SELECT pg_advisory_unlock(hashtext('pico.cron_jobs'), hashtext($1))",
+                &[&job.name],
+            )
+            .map_err(|e| format!("error unlocking cron {}: {}", job.name, e))?;
+        runs.map(|runs| !runs.is_empty())
+    }
+
+    /// Picks the runs that came due since the last one claimed, following the job's
+    /// CATCH_UP, and moves the job's row in pico.cron_jobs past them, so no instance runs
+    /// them again
+    fn claim_runs(
+        client: &mut Client,
+        job: &CronJob,
+        now: NaiveDateTime,
+    ) -> Result<Vec<NaiveDateTime>, String> {
+        let error = |e: postgres::Error| format!("error claiming runs of cron {}: {}", job.name, e);
+        let mut transaction = client.transaction().map_err(error)?;
+        let last: NaiveDateTime = transaction
+            .query_one(
+                "SELECT (last_scheduled_at AT TIME ZONE $2)::timestamp FROM pico.cron_jobs
+                WHERE name = $1 FOR UPDATE",
+                &[&job.name, &job.timezone],
+            )
+            .map(|row| row.get(0))
+            .map_err(error)?;
+
+        let due = job.catch_up.select_runs(&job.schedule, last, now);
+        let latest = match due.latest {
+            Some(latest) => latest,
+            None => return Ok(vec![]),
+        };
+        if due.skipped {
+            info!("Cron {} skipped runs missed since {}", job.name, last);
+        }
+
+        transaction
+            .execute(
+                "UPDATE pico.cron_jobs SET last_scheduled_at = $2::timestamp AT TIME ZONE $3
+                WHERE name = $1",
+                &[&job.name, &latest, &job.timezone],
+            )
+            .map_err(error)?;
+        transaction.commit().map_err(error)?;
+        Ok(due.runs)
+    }
+
+    /// Runs a job in a transaction of its own and records the run in pico.cron_runs
+    fn run_job(lua: &Lua, sql: &mut SQL, job: &CronJob, scheduled_at: NaiveDateTime) {
+        info!(
+            "Running cron {} scheduled at {} {}",
+            job.name, scheduled_at, job.timezone
+        );
+        let run = sql.connection.query_one(
+            "INSERT INTO pico.cron_runs(name, scheduled_at, status)
+            VALUES ($1, $2::timestamp AT TIME ZONE $3, 'running')
+            RETURNING id",
+            &[&job.name, &scheduled_at, &job.timezone],
+        );
+        let run_id: i64 = match run {
+            Ok(row) => row.get(0),
+            Err(e) => {
+                error!("Error recording the run of cron {}: {}", job.name, e);
+                return;
+            }
+        };
+
+        let started = Instant::now();
+        let result = execute_job(lua, sql, job, scheduled_at);
+        let result = match sql.finish_request(result.is_ok()) {
+            Ok(()) => result,
+            Err(e) => result.and(Err(e)),
+        };
+        let (status, error, result) = match result {
+            Ok(result) => ("succeeded", None, result),
+            Err(e) => match job.timeout {
+                // Whatever failed, it was cut short by the deadline
+                Some(timeout) if started.elapsed() >= timeout => {
+                    error!("Cron {} timed out after {:?}: {}", job.name, timeout, e);
+                    let e = format!("timed out after {:?}: {}", timeout, e);
+                    ("timed_out", Some(e), JsonValue::Null)
+                }
+                _ => {
+                    error!("Cron {} failed: {}", job.name, e);
+                    ("failed", Some(e), JsonValue::Null)
+                }
+            },
+        };
+        if let Err(e) = sql.connection.execute(
+            "UPDATE pico.cron_runs SET
+                status = $2,
+                error = $3,
+                result = $4,
+                finished_at = clock_timestamp(),
+                duration_ms = (EXTRACT(EPOCH FROM clock_timestamp() - started_at) * 1000)::int8
+            WHERE id = $1",
+            &[&run_id, &status, &error, &result],
+        ) {
+            error!("Error recording the end of cron {}: {}", job.name, e);
+        }
+    }
+
+    /// PREPROCESS returns the parameters of the job's SQL, and POSTPROCESS the result that
+    /// is recorded. Both can use pico.sql, in the job's transaction.
+    fn execute_job(
+        lua: &Lua,
+        sql: &mut SQL,
+        job: &CronJob,
+        scheduled_at: NaiveDateTime,
+    ) -> Result<JsonValue, String> {
+        let lua_error = |hook: &str, e: mlua::Error| {
+            format!("{} failed: {}", hook, extract_lua_error_message(&e))
+        };
+        let context = || SqlContext {
+            rls: None,
+            claims: None,
+        };
+        let info = lua
+            .create_table()
+            .and_then(|info| {
+                info.set("name", job.name.as_str())?;
+                info.set("scheduled_at", scheduled_at.to_string())?;
+                info.set("timezone", job.timezone.as_str())?;
+                Ok(info)
+            })
+            .map_err(|e| e.to_string())?;
+
+        // The hooks share the job's deadline, or SANDBOX.TIMEOUT without one
+        let started = Instant::now();
+        let timer = match job.timeout {
+            Some(timeout) => HandlerTimer::start_with(lua, Some(timeout)),
+            None => HandlerTimer::start(lua),
+        };
+        let remaining = || job.timeout.map(|t| t.saturating_sub(started.elapsed()));
+        if let Some(remaining) = remaining() {
+            sql.limit_request(remaining)?;
+        }
+
+        let mut params: HashMap<String, JsonValue> = HashMap::new();
+        if let Some(pre_process) = &job.pre_process {
+            let value = timer
+                .check(with_request_sql(lua, sql, context(), || {
+                    pre_process.call::<Value>(&info)
+                }))
+                .map_err(|e| lua_error("PREPROCESS", e))?;
+            params = match value {
+                Value::Nil => HashMap::new(),
+                Value::Table(_) => lua
+                    .from_value(value)
+                    .map_err(|e| lua_error("PREPROCESS", e))?,
+                other => {
+                    return Err(format!(
+                        "PREPROCESS returned a {}, expected a table of parameters or nil",
+                        other.type_name()
+                    ));
+                }
+            };
+        }
+
+        let mut result = JsonValue::Null;
+        if let Some(file_name) = &job.sql_function_name {
+            let name = file_name.strip_suffix(".sql").unwrap_or(file_name);
+            let function = sql
+                .functions
+                .get(name)
+                .ok_or_else(|| format!("unknown SQL function {}", name))?;
+            if let Some(param) = function
+                .parameters
+                .iter()
+                .find(|p| !params.contains_key(*p))
+            {
+                return Err(format!("{} is missing parameter {}", name, param));
+            }
+            match remaining() {
+                Some(remaining) => sql.limit_request(remaining)?,
+                None => sql
+                    .begin_request(None, None)
+                    .map_err(|_| "error starting the cron transaction".to_string())?,
+            }
+            result = match sql.functions[name].execute(&mut sql.connection, params) {
+                Ok(value) => value,
+                Err(rc) => {
+                    sql.fail_request();
+                    return Err(format!("{} failed: {}", name, rc.to_str()));
+                }
+            };
+        }
+
+        if let Some(post_process) = &job.post_process {
+            let value = timer
+                .check(with_request_sql(lua, sql, context(), || {
+                    post_process.call::<Value>((lua.to_value(&result)?, &info))
+                }))
+                .map_err(|e| lua_error("POSTPROCESS", e))?;
+            result = lua
+                .from_value(value)
+                .map_err(|e| lua_error("POSTPROCESS", e))?;
+        }
+        Ok(result)
+    }
 }
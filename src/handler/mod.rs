@@ -0,0 +1,115 @@
+pub mod handler {
+    use std::{collections::HashMap, fmt};
+
+    use serde_json::Value;
+
+    use crate::{
+        PicoRequest,
+        http::http::{PicoResponse, ResponseCode},
+        sql::sql::{RlsConfig, SQL},
+    };
+
+    /// A route handler written in Rust, standing in for the route's SQL. Its result is the
+    /// response body handed to SETJWT, POSTPROCESS and the VIEW.
+    pub type RustHandler = dyn Fn(&mut RustContext) -> Result<Value, PicoResponse>;
+
+    /// A Rust PREPROCESS, transforming ctx.input before the route's SQL or Rust handler
+    pub type RustPreprocess = dyn Fn(&mut RustContext) -> Result<(), PicoResponse>;
+
+    /// A Rust POSTPROCESS, transforming the response body after the Lua POSTPROCESS
+    pub type RustPostprocess = dyn Fn(&mut RustContext, Value) -> Result<Value, PicoResponse>;
+
+    /// Native handlers registered on a route with PicoService or PicoServiceBuilder
+    #[derive(Default)]
+    pub struct RustHooks {
+        pub handler: Option<Box<RustHandler>>,
+        pub pre_process: Option<Box<RustPreprocess>>,
+        pub post_process: Option<Box<RustPostprocess>>,
+    }
+
+    impl fmt::Debug for RustHooks {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("RustHooks")
+                .field("handler", &self.handler.is_some())
+                .field("pre_process", &self.pre_process.is_some())
+                .field("post_process", &self.post_process.is_some())
+                .finish()
+        }
+    }
+
+    impl PartialEq for RustHooks {
+        fn eq(&self, other: &Self) -> bool {
+            fn same<F: ?Sized>(a: &Option<Box<F>>, b: &Option<Box<F>>) -> bool {
+                match (a, b) {
+                    (Some(a), Some(b)) => std::ptr::addr_eq(&**a, &**b),
+                    (None, None) => true,
+                    _ => false,
+                }
+            }
+            same(&self.handler, &other.handler)
+                && same(&self.pre_process, &other.pre_process)
+                && same(&self.post_process, &other.post_process)
+        }
+    }
+
+    /// What a Rust handler or hook sees of the request, the Rust counterpart of the
+    /// parameters, JWT and ctx Lua hooks are called with
+    pub struct RustContext<'a> {
+        pub request: &'a PicoRequest,
+        pub params: HashMap<String, String>, // Route parameters, named without the leading ':'
+        pub input: HashMap<String, Value>,   // Squashed request parameters, empty in POSTPROCESS
+        pub claims: Option<&'a Value>,       // Claims of the request's JWT or API key
+        pub(crate) sql: &'a mut SQL,
+        pub(crate) rls: Option<&'a RlsConfig>,
+    }
+
+    impl RustContext<'_> {
+        /// First value of a request header, by its lowercase name
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.request
+                .headers
+                .get(name)
+                .and_then(|values| values.first())
+                .map(|value| value.as_str())
+        }
+
+        /// Runs a query in the request's transaction, as the RLS role of the request, and
+        /// returns its rows as JSON objects. A failed query rolls the request back.
+        pub fn query(&mut self, text: &str, params: &[Value]) -> Result<Vec<Value>, String> {
+            self.sql
+                .begin_request(self.rls, self.claims)
+                .map_err(|_| "error starting the request transaction".to_string())?;
+            self.sql.query_json(text, params)
+        }
+
+        /// Calls a SQL function from functions/ in the request's transaction, like the SQL
+        /// of a route. Missing parameters are an error.
+        pub fn call(&mut self, name: &str, input: HashMap<String, Value>) -> Result<Value, String> {
+            let function = self
+                .sql
+                .functions
+                .get(name)
+                .ok_or_else(|| format!("unknown SQL function {}", name))?;
+            if let Some(param) = function.parameters.iter().find(|p| !input.contains_key(*p)) {
+                return Err(format!("{} is missing parameter {}", name, param));
+            }
+            self.sql
+                .begin_request(self.rls, self.claims)
+                .map_err(|_| "error starting the request transaction".to_string())?;
+
+            let sql = &mut *self.sql;
+            match sql.functions[name].execute(&mut sql.connection, input) {
+                Ok(value) => Ok(value),
+                // Parameters that don't convert to their SQL types never reach Postgres
+                Err(ResponseCode::BadRequest) => Err(format!(
+                    "parameters of {} don't match their SQL types",
+                    name
+                )),
+                Err(_) => {
+                    sql.fail_request();
+                    Err(format!("{} failed, see the server log for details", name))
+                }
+            }
+        }
+    }
+}
@@ -7,12 +7,12 @@ pub mod html {
 
     use crate::route::route::Method;
 
-    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
     pub struct View {
         entities: Vec<Entity>,
     }
 
-    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
     pub enum Entity {
         Links(Vec<Link>),
         Form(Form),
@@ -21,13 +21,13 @@ pub mod html {
         Table(HtmlTable),
     }
 
-    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
     pub struct Link {
         value: String,
         label: Option<String>,
     }
 
-    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
     pub struct Form {
         target: String,
         method: Method,
@@ -35,7 +35,7 @@ pub mod html {
         fields: Vec<Field>,
     }
 
-    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
     pub struct Field {
         id: String,
         field_type: String,
@@ -43,19 +43,20 @@ pub mod html {
         value: Option<String>,
     }
 
-    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
     pub struct HtmlTable {
         columns: Vec<Column>,
     }
 
-    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
     pub struct Column {
         name: String,
         accessor: Option<String>,
     }
 
     impl View {
-        pub fn to_html(&self, data: serde_json::Value) -> String {
+        /// Renders the view for the data, embedding the CSRF token in forms and htmx requests
+        pub fn to_html(&self, data: serde_json::Value, csrf_token: &str) -> String {
             // Initialize Handlebars registry
             let mut handlebars = Handlebars::new();
 
@@ -94,7 +95,8 @@ pub mod html {
                             "target": form.target,
                             "method_lower": form.method.to_string().to_lowercase(),
                             "title": form.title,
-                            "fields": form.fields
+                            "fields": form.fields,
+                            "csrf_token": csrf_token
                         });
                         handlebars
                             .render("form", &context)
@@ -182,7 +184,7 @@ pub mod html {
             }
 
             // Render the complete page with layout
-            let layout_context = json!({ "content": content });
+            let layout_context = json!({ "content": content, "csrf_token": csrf_token });
             let html = handlebars
                 .render("layout", &layout_context)
                 .expect("Failed to render layout template");
@@ -1,7 +1,6 @@
 pub mod http {
     use log::{debug, error, warn};
-    use regex::Regex;
-    use serde_json::{json, Value};
+    use serde_json::{json, Map, Value};
     use std::{collections::HashMap, io::Read, net::TcpStream, time::Duration, vec};
 
     use crate::{PicoRequest, route::route::Method};
@@ -20,46 +19,70 @@ pub mod http {
     #[derive(Debug, Clone)]
     pub enum ResponseCode {
         Ok,
+        NoContent,
+        Found,
+        PermanentRedirect,
         NotFound,
         InternalError,
         BadRequest,
         Unauthorized,
+        Forbidden,
+        TooManyRequests,
         HeaderFieldsTooLarge,
+        ServiceUnavailable,
     }
 
     impl ResponseCode {
         pub fn to_str(&self) -> &str {
             match self {
                 ResponseCode::Ok => "OK",
+                ResponseCode::NoContent => "No Content",
+                ResponseCode::Found => "Found",
+                ResponseCode::PermanentRedirect => "Permanent Redirect",
                 ResponseCode::NotFound => "Not Found",
                 ResponseCode::InternalError => "Internal Server Error",
                 ResponseCode::BadRequest => "Bad Request",
                 ResponseCode::Unauthorized => "Unauthorized",
+                ResponseCode::Forbidden => "Forbidden",
+                ResponseCode::TooManyRequests => "Too Many Requests",
                 ResponseCode::HeaderFieldsTooLarge => "Header Fields Too Large",
+                ResponseCode::ServiceUnavailable => "Service Unavailable",
             }
         }
 
         pub fn to_code(&self) -> u16 {
             match self {
                 ResponseCode::Ok => 200,
+                ResponseCode::NoContent => 204,
+                ResponseCode::Found => 302,
+                ResponseCode::PermanentRedirect => 308,
                 ResponseCode::NotFound => 404,
                 ResponseCode::InternalError => 500,
                 ResponseCode::BadRequest => 400,
                 ResponseCode::Unauthorized => 401,
+                ResponseCode::Forbidden => 403,
+                ResponseCode::TooManyRequests => 429,
                 ResponseCode::HeaderFieldsTooLarge => 431,
+                ResponseCode::ServiceUnavailable => 503,
             }
         }
 
         pub fn to_bytes(&self) -> &[u8] {
             match self {
                 ResponseCode::Ok => b"HTTP/1.1 200 OK\r\n\r\n",
+                ResponseCode::NoContent => b"HTTP/1.1 204 No Content\r\n\r\n",
+                ResponseCode::Found => b"HTTP/1.1 302 Found\r\n\r\n",
+                ResponseCode::PermanentRedirect => b"HTTP/1.1 308 Permanent Redirect\r\n\r\n",
                 ResponseCode::NotFound => b"HTTP/1.1 404 Not Found\r\n\r\n",
                 ResponseCode::InternalError => b"HTTP/1.1 500 Internal Server Error\r\n\r\n",
                 ResponseCode::BadRequest => b"HTTP/1.1 400 Bad Request\r\n\r\n",
                 ResponseCode::Unauthorized => b"HTTP/1.1 401 Unauthorized\r\n\r\n",
+                ResponseCode::Forbidden => b"HTTP/1.1 403 Forbidden\r\n\r\n",
+                ResponseCode::TooManyRequests => b"HTTP/1.1 429 Too Many Requests\r\n\r\n",
                 ResponseCode::HeaderFieldsTooLarge => {
                     b"HTTP/1.1 431 Header Fields Too Large\r\n\r\n"
                 }
+                ResponseCode::ServiceUnavailable => b"HTTP/1.1 503 Service Unavailable\r\n\r\n",
             }
         }
     }
@@ -88,7 +111,10 @@ pub mod http {
             });
 
             let mut headers = HashMap::new();
-            headers.insert("Content-Type".to_string(), vec!["application/json".to_string()]);
+            headers.insert(
+                "Content-Type".to_string(),
+                vec!["application/json".to_string()],
+            );
 
             Self {
                 status,
@@ -97,6 +123,18 @@ pub mod http {
             }
         }
 
+        pub fn redirect(status: ResponseCode, location: &str) -> Self {
+            let mut headers = HashMap::new();
+            headers.insert("Location".to_string(), vec![location.to_string()]);
+            headers.insert("Content-Length".to_string(), vec!["0".to_string()]);
+
+            Self {
+                status,
+                body: vec![],
+                headers,
+            }
+        }
+
         pub fn to_http_bytes(&self) -> Vec<u8> {
             let status_line = format!(
                 "HTTP/1.1 {} {}\r\n",
@@ -106,6 +144,13 @@ pub mod http {
 
             let mut headers_str = String::new();
             for (key, values) in &self.headers {
+                // Each cookie needs its own Set-Cookie line, they can't be folded together
+                if key.eq_ignore_ascii_case("set-cookie") {
+                    for value in values {
+                        headers_str.push_str(&format!("{}: {}\r\n", key, value));
+                    }
+                    continue;
+                }
                 for (i, value) in values.iter().enumerate() {
                     if i == 0 {
                         headers_str.push_str(&format!("{}: {}", key, value));
@@ -298,7 +343,8 @@ pub mod http {
         }
 
         let mut path = String::new();
-        let mut query: HashMap<String, String> = HashMap::new();
+        let mut raw_query = String::new();
+        let mut query: HashMap<String, Value> = HashMap::new();
         let split_path: Vec<&str> = http_request.path.split('?').collect();
         if split_path.len() == 1 {
             path = split_path[0].to_string();
@@ -306,6 +352,7 @@ pub mod http {
             path = split_path[0].to_string();
             let query_string = split_path[1];
             if query_string != "" {
+                raw_query = query_string.to_string();
                 query = parse_query_parameters(query_string);
             }
         }
@@ -320,29 +367,68 @@ pub mod http {
             method,
             path,
             query,
+            raw_query,
             version: http_request.version,
             headers: header_map,
             body,
+            remote_addr: stream.peer_addr().ok().map(|addr| addr.ip()),
         })
     }
 
-    fn parse_query_parameters(query: &str) -> HashMap<String, String> {
-        let mut queries: HashMap<String, String> = HashMap::new();
-
-        let r = Regex::new(r"(\w+)=(\w+)").unwrap();
+    /// Parses a query string like a form body. Repeated keys and keys ending in [] collect
+    /// into arrays, and bracketed keys like user[name] build nested objects.
+    pub fn parse_query_parameters(query: &str) -> HashMap<String, Value> {
+        let mut params = Map::new();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            if key.is_empty() {
+                continue;
+            }
+            insert_query_value(&mut params, &query_key_path(&key), Value::String(value.into_owned()));
+        }
+        params.into_iter().collect()
+    }
 
-        for caps in r.captures_iter(query) {
-            let key = match caps.get(1) {
-                Some(c) => c,
-                None => continue,
-            };
-            let value = match caps.get(2) {
-                Some(c) => c,
-                None => continue,
-            };
-            queries.insert(key.as_str().to_string(), value.as_str().to_string());
+    /// Splits a key like user[address][city] into user, address and city
+    fn query_key_path(key: &str) -> Vec<&str> {
+        match key.find('[') {
+            Some(start) if start > 0 && key.ends_with(']') => {
+                let mut path = vec![&key[..start]];
+                path.extend(key[start + 1..key.len() - 1].split("]["));
+                path
+            }
+            _ => vec![key],
         }
+    }
 
-        return queries;
+    fn insert_query_value(params: &mut Map<String, Value>, path: &[&str], value: Value) {
+        let key = path[0].to_string();
+        match path.get(1) {
+            // A repeated key turns into an array of its values, key[] always is one
+            None | Some(&"") => {
+                let appending = path.len() > 1;
+                match params.get_mut(&key) {
+                    Some(Value::Array(values)) => values.push(value),
+                    Some(existing) => {
+                        let first = existing.take();
+                        *existing = Value::Array(vec![first, value]);
+                    }
+                    None if appending => {
+                        params.insert(key, Value::Array(vec![value]));
+                    }
+                    None => {
+                        params.insert(key, value);
+                    }
+                }
+            }
+            Some(_) => {
+                let nested = params.entry(key).or_insert_with(|| Value::Object(Map::new()));
+                if !nested.is_object() {
+                    *nested = Value::Object(Map::new());
+                }
+                if let Value::Object(nested) = nested {
+                    insert_query_value(nested, &path[1..], value);
+                }
+            }
+        }
     }
 }
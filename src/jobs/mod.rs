@@ -0,0 +1,493 @@
+pub mod jobs {
+    use std::{
+        collections::HashMap,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use log::{debug, error, info, warn};
+    use mlua::{FromLua, Function, Lua, LuaSerdeExt, Table, Value};
+    use postgres::Client;
+    use serde_json::Value as JsonValue;
+
+    use crate::{
+        extract_lua_error_message, lua_duration,
+        pico::pico::{SqlContext, with_request_sql},
+        sandbox::sandbox::{HandlerTimer, install_timeout_hook},
+        sql::sql::SQL,
+    };
+
+    /// How long a worker waits before looking for due jobs again, after finding none
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// How long past its TIMEOUT a claimed job waits for its worker before another can
+    /// claim it, for when the instance running it stopped
+    const LEASE_MARGIN: Duration = Duration::from_secs(60);
+
+    /// Longest wait between two attempts of a job
+    const MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Longest a worker waits before claiming again after errors that left the connection
+    /// usable
+    const MAX_ERROR_DELAY: Duration = Duration::from_secs(60);
+
+    const DEFAULT_MAX_ATTEMPTS: i32 = 5;
+    const DEFAULT_BACKOFF: Duration = Duration::from_secs(10);
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+    /// Jobs declared with JOBS, run from pico.jobs by a background worker
+    #[derive(Debug, PartialEq)]
+    pub struct Jobs {
+        pub jobs: Vec<Job>, // Sorted by name
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub struct Job {
+        pub name: String,
+        pub sql_function_name: Option<String>,
+        pub handler: Option<Function>,
+        pub max_attempts: i32, // Used when pico.enqueue wasn't given max_attempts
+        pub backoff: Duration, // Wait after the first failed attempt, doubled after each other
+        pub timeout: Duration, // Longest an attempt can take before it's rolled back
+    }
+
+    /// Names of the declared jobs, kept in the Lua state so pico.enqueue can check them
+    pub struct JobNames(pub Vec<String>);
+
+    /// When an enqueued job runs, from pico.enqueue's run_at
+    pub enum RunAt {
+        In(Duration),
+        At(String), // A timestamp, read by Postgres
+    }
+
+    /// A job from pico.failed_jobs, where jobs go once they run out of attempts
+    pub struct FailedJob {
+        pub id: i64,
+        pub name: String,
+        pub payload: JsonValue,
+        pub attempts: i32,
+        pub error: Option<String>,
+        pub failed_at: String,
+    }
+
+    /// A job claimed from pico.jobs
+    struct QueuedJob {
+        id: i64,
+        name: String,
+        payload: JsonValue,
+        attempts: i32, // Including the one being run
+        max_attempts: Option<i32>,
+    }
+
+    impl FromLua for Jobs {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            let conversion_error = |message: String| mlua::Error::FromLuaConversionError {
+                from: "table",
+                to: "pico::jobs::Jobs".to_string(),
+                message: Some(message),
+            };
+            let t = match value {
+                Value::Table(t) => t,
+                _ => return Err(conversion_error("expected JOBS to be a table".to_string())),
+            };
+            let duration = |job: &Table, name: &str, setting: &str| -> mlua::Result<_> {
+                match job.get::<Option<Value>>(setting)? {
+                    Some(v) => lua_duration(&v)
+                        .filter(|d| !d.is_zero())
+                        .map(Some)
+                        .ok_or_else(|| {
+                            conversion_error(format!(
+                                "invalid {} {}, expected seconds or a duration like '5m'",
+                                name, setting
+                            ))
+                        }),
+                    None => Ok(None),
+                }
+            };
+
+            let mut jobs = vec![];
+            for pair in t.pairs::<String, Table>() {
+                let (name, job) = pair.map_err(|e| {
+                    conversion_error(format!("expected JOBS to map job names to tables, {}", e))
+                })?;
+                let sql_function_name: Option<String> = job.get("SQL")?;
+                let handler: Option<Function> = job.get("HANDLER")?;
+                if sql_function_name.is_none() && handler.is_none() {
+                    return Err(conversion_error(format!(
+                        "{} has nothing to run, expected SQL or HANDLER",
+                        name
+                    )));
+                }
+                let max_attempts = match job.get::<Option<i32>>("MAX_ATTEMPTS")? {
+                    Some(n) if n < 1 => {
+                        return Err(conversion_error(format!(
+                            "{} MAX_ATTEMPTS must be at least 1",
+                            name
+                        )));
+                    }
+                    Some(n) => n,
+                    None => DEFAULT_MAX_ATTEMPTS,
+                };
+                let backoff = duration(&job, &name, "BACKOFF")?.unwrap_or(DEFAULT_BACKOFF);
+                let timeout = duration(&job, &name, "TIMEOUT")?.unwrap_or(DEFAULT_TIMEOUT);
+                jobs.push(Job {
+                    name,
+                    sql_function_name,
+                    handler,
+                    max_attempts,
+                    backoff,
+                    timeout,
+                });
+            }
+            jobs.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(Jobs { jobs })
+        }
+    }
+
+    impl Jobs {
+        pub fn names(&self) -> JobNames {
+            JobNames(self.jobs.iter().map(|job| job.name.clone()).collect())
+        }
+    }
+
+    impl Job {
+        /// Wait before the next attempt, after the given number of failed ones
+        pub(crate) fn backoff_after(&self, attempts: i32) -> Duration {
+            let doublings = attempts.saturating_sub(1).clamp(0, 31) as u32;
+            self.backoff
+                .saturating_mul(2u32.saturating_pow(doublings))
+                .min(MAX_BACKOFF)
+        }
+    }
+
+    /// Creates the pico.jobs queue, and the pico.failed_jobs table jobs are moved to once
+    /// they run out of attempts
+    pub fn initialize_jobs(client: &mut Client) -> Result<(), String> {
+        client
+            .batch_execute(
+                "CREATE SCHEMA IF NOT EXISTS pico;
+                CREATE TABLE IF NOT EXISTS pico.jobs(
+                    id BIGSERIAL PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    payload JSONB NOT NULL,
+                    run_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                    attempts INT NOT NULL DEFAULT 0,
+                    max_attempts INT,
+                    last_error TEXT,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                );
+                CREATE INDEX IF NOT EXISTS jobs_run_at_idx ON pico.jobs(run_at);
+                CREATE TABLE IF NOT EXISTS pico.failed_jobs(
+                    id BIGINT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    payload JSONB NOT NULL,
+                    attempts INT NOT NULL,
+                    max_attempts INT,
+                    error TEXT,
+                    created_at TIMESTAMPTZ NOT NULL,
+                    failed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                );",
+            )
+            .map_err(|e| {
+                format!(
+                    "error creating pico.jobs and pico.failed_jobs tables: {}",
+                    e
+                )
+            })
+    }
+
+    /// Adds a job to pico.jobs and returns its id. Enqueued in a transaction, the job only
+    /// runs once it commits.
+    pub fn enqueue(
+        client: &mut Client,
+        name: &str,
+        payload: &JsonValue,
+        run_at: Option<&RunAt>,
+        max_attempts: Option<i32>,
+    ) -> Result<i64, String> {
+        let (delay, at) = match run_at {
+            Some(RunAt::In(delay)) => (delay.as_secs_f64(), None),
+            Some(RunAt::At(at)) => (0.0, Some(at.as_str())),
+            None => (0.0, None),
+        };
+        client
+            .query_one(
+                "INSERT INTO pico.jobs(name, payload, run_at, max_attempts)
+                VALUES ($1, $2, COALESCE($3::text::timestamptz, now() + make_interval(secs => $4)), $5)
+                RETURNING id",
+                &[&name, payload, &at, &delay, &max_attempts],
+            )
+            .map(|row| row.get(0))
+            .map_err(|e| e.to_string())
+    }
+
+    /// The jobs in pico.failed_jobs, the latest failed first
+    pub fn list_failed_jobs(client: &mut Client) -> Result<Vec<FailedJob>, String> {
+        let rows = client
+            .query(
+                "SELECT id, name, payload, attempts, error, failed_at::text
+                FROM pico.failed_jobs
+                ORDER BY failed_at DESC, id DESC",
+                &[],
+            )
+            .map_err(|e| format!("error reading pico.failed_jobs: {}", e))?;
+        Ok(rows
+            .iter()
+            .map(|row| FailedJob {
+                id: row.get(0),
+                name: row.get(1),
+                payload: row.get(2),
+                attempts: row.get(3),
+                error: row.get(4),
+                failed_at: row.get(5),
+            })
+            .collect())
+    }
+
+    /// Moves failed jobs back to pico.jobs, to run now with their attempts reset. All of
+    /// them without ids. Returns how many were moved.
+    pub fn retry_failed_jobs(client: &mut Client, ids: Option<&[i64]>) -> Result<u64, String> {
+        client
+            .execute(
+                "WITH retried AS (
+                    DELETE FROM pico.failed_jobs WHERE $1::int8[] IS NULL OR id = ANY($1)
+                    RETURNING *
+                )
+                INSERT INTO pico.jobs(id, name, payload, max_attempts, last_error, created_at)
+                SELECT id, name, payload, max_attempts, error, created_at FROM retried",
+                &[&ids],
+            )
+            .map_err(|e| format!("error retrying failed jobs: {}", e))
+    }
+
+    /// Runs due jobs from pico.jobs, one at a time, until the connection is lost. Other
+    /// errors claiming jobs are logged, and claiming is tried again after a wait that
+    /// doubles while they go on. Every instance of the app runs a worker, and each job is
+    /// claimed by one.
+    pub fn run_worker(lua: &Lua, sql: &mut SQL, jobs: &Jobs) -> Result<(), String> {
+        install_timeout_hook(lua).map_err(|e| format!("error setting TIMEOUT: {}", e))?;
+        let mut error_delay = POLL_INTERVAL;
+        loop {
+            let queued = match claim_job(&mut sql.connection, jobs) {
+                Ok(queued) => {
+                    error_delay = POLL_INTERVAL;
+                    queued
+                }
+                Err(e) if sql.connection.is_closed() => return Err(e),
+                Err(e) => {
+                    error!("{}, claiming again in {:?}", e, error_delay);
+                    thread::sleep(error_delay);
+                    error_delay = (error_delay * 2).min(MAX_ERROR_DELAY);
+                    continue;
+                }
+            };
+            let job = queued
+                .as_ref()
+                .and_then(|queued| jobs.jobs.iter().find(|job| job.name == queued.name));
+            match (queued, job) {
+                (Some(queued), Some(job)) => run_job(lua, sql, job, queued),
+                _ => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+
+    /// Claims the job due the longest, skipping the ones other workers are claiming. The
+    /// claim counts as an attempt and moves the job's run_at past its TIMEOUT, so it runs
+    /// again if this worker never finishes it.
+    fn claim_job(client: &mut Client, jobs: &Jobs) -> Result<Option<QueuedJob>, String> {
+        let names: Vec<&str> = jobs.jobs.iter().map(|job| job.name.as_str()).collect();
+        let leases: Vec<f64> = jobs
+            .jobs
+            .iter()
+            .map(|job| (job.timeout + LEASE_MARGIN).as_secs_f64())
+            .collect();
+        let row = client
+            .query_opt(
+                "WITH due AS (
+                    SELECT jobs.id, leases.seconds
+                    FROM pico.jobs
+                    JOIN unnest($1::text[], $2::float8[]) AS leases(name, seconds) USING (name)
+                    WHERE jobs.run_at <= now()
+                    ORDER BY jobs.run_at, jobs.id
+                    LIMIT 1
+                    FOR UPDATE OF jobs SKIP LOCKED
+                )
+                UPDATE pico.jobs SET
+                    attempts = jobs.attempts + 1,
+                    run_at = now() + make_interval(secs => due.seconds)
+                FROM due
+                WHERE jobs.id = due.id
+                RETURNING jobs.id, jobs.name, jobs.payload, jobs.attempts, jobs.max_attempts",
+                &[&names, &leases],
+            )
+            .map_err(|e| format!("error claiming a job: {}", e))?;
+        Ok(row.map(|row| QueuedJob {
+            id: row.get(0),
+            name: row.get(1),
+            payload: row.get(2),
+            attempts: row.get(3),
+            max_attempts: row.get(4),
+        }))
+    }
+
+    /// Runs an attempt of a job in a transaction of its own, which removes the job from
+    /// pico.jobs when it commits. A failed attempt is retried after the job's backoff, or
+    /// moved to pico.failed_jobs when it was the last.
+    fn run_job(lua: &Lua, sql: &mut SQL, job: &Job, queued: QueuedJob) {
+        let max_attempts = queued.max_attempts.unwrap_or(job.max_attempts);
+        info!(
+            "Running job {} {}, attempt {} of {}",
+            job.name, queued.id, queued.attempts, max_attempts
+        );
+        let started = Instant::now();
+        let result = execute_job(lua, sql, job, &queued, max_attempts);
+        let result = match sql.finish_request(result.is_ok()) {
+            Ok(()) => result,
+            Err(e) => result.and(Err(e)),
+        };
+        let e = match result {
+            Ok(()) => {
+                debug!(
+                    "Job {} {} done in {:?}",
+                    job.name,
+                    queued.id,
+                    started.elapsed()
+                );
+                return;
+            }
+            // Whatever failed, it was cut short by the deadline
+            Err(e) if started.elapsed() >= job.timeout => {
+                format!("timed out after {:?}: {}", job.timeout, e)
+            }
+            Err(e) => e,
+        };
+
+        if let Err(e) = record_failure(
+            &mut sql.connection,
+            job,
+            queued.id,
+            queued.attempts,
+            max_attempts,
+            &e,
+        ) {
+            error!(
+                "Error recording the failure of job {} {}: {}",
+                job.name, queued.id, e
+            );
+        }
+    }
+
+    /// Schedules the next attempt of a job after its backoff, or moves it to
+    /// pico.failed_jobs when the failed attempt was its last
+    pub(crate) fn record_failure(
+        client: &mut Client,
+        job: &Job,
+        id: i64,
+        attempts: i32,
+        max_attempts: i32,
+        error: &str,
+    ) -> Result<(), String> {
+        let recorded = if attempts >= max_attempts {
+            error!(
+                "Job {} {} failed {} times, moving it to pico.failed_jobs: {}",
+                job.name, id, attempts, error
+            );
+            client.execute(
+                "WITH failed AS (DELETE FROM pico.jobs WHERE id = $1 RETURNING *)
+                INSERT INTO pico.failed_jobs(id, name, payload, attempts, max_attempts, error, created_at)
+                SELECT id, name, payload, attempts, max_attempts, $2, created_at FROM failed",
+                &[&id, &error],
+            )
+        } else {
+            let backoff = job.backoff_after(attempts);
+            warn!(
+                "Job {} {} failed, retrying in {:?}: {}",
+                job.name, id, backoff, error
+            );
+            client.execute(
+                "UPDATE pico.jobs SET
+                    run_at = now() + make_interval(secs => $2),
+                    last_error = $3
+                WHERE id = $1",
+                &[&id, &backoff.as_secs_f64(), &error],
+            )
+        };
+        recorded.map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    /// HANDLER receives the payload and returns the parameters of the job's SQL, or nil to
+    /// pass it the payload. It can use pico.sql, in the job's transaction.
+    fn execute_job(
+        lua: &Lua,
+        sql: &mut SQL,
+        job: &Job,
+        queued: &QueuedJob,
+        max_attempts: i32,
+    ) -> Result<(), String> {
+        let started = Instant::now();
+        let timer = HandlerTimer::start_with(lua, Some(job.timeout));
+        let remaining = || job.timeout.saturating_sub(started.elapsed());
+        sql.limit_request(remaining())?;
+        // Rolled back with the attempt when it fails
+        if let Err(e) = sql
+            .connection
+            .execute("DELETE FROM pico.jobs WHERE id = $1", &[&queued.id])
+        {
+            sql.fail_request();
+            return Err(format!("error removing the job from pico.jobs: {}", e));
+        }
+
+        let mut params = queued.payload.clone();
+        if let Some(handler) = &job.handler {
+            let lua_error =
+                |e: mlua::Error| format!("HANDLER failed: {}", extract_lua_error_message(&e));
+            let info = lua
+                .create_table()
+                .and_then(|info| {
+                    info.set("id", queued.id)?;
+                    info.set("name", job.name.as_str())?;
+                    info.set("attempt", queued.attempts)?;
+                    info.set("max_attempts", max_attempts)?;
+                    Ok(info)
+                })
+                .map_err(|e| e.to_string())?;
+            let context = SqlContext {
+                rls: None,
+                claims: None,
+            };
+            let value = timer
+                .check(with_request_sql(lua, sql, context, || {
+                    handler.call::<Value>((lua.to_value(&queued.payload)?, &info))
+                }))
+                .map_err(lua_error)?;
+            if !value.is_nil() {
+                params = lua.from_value(value).map_err(lua_error)?;
+            }
+        }
+
+        if let Some(file_name) = &job.sql_function_name {
+            let name = file_name.strip_suffix(".sql").unwrap_or(file_name);
+            let params: HashMap<String, JsonValue> = match params {
+                JsonValue::Object(params) => params.into_iter().collect(),
+                _ => return Err(format!("the parameters of {} are not a table", name)),
+            };
+            let function = sql
+                .functions
+                .get(name)
+                .ok_or_else(|| format!("unknown SQL function {}", name))?;
+            if let Some(param) = function
+                .parameters
+                .iter()
+                .find(|p| !params.contains_key(*p))
+            {
+                return Err(format!("{} is missing parameter {}", name, param));
+            }
+            sql.limit_request(remaining())?;
+            if let Err(rc) = sql.functions[name].execute(&mut sql.connection, params) {
+                sql.fail_request();
+                return Err(format!("{} failed: {}", name, rc.to_str()));
+            }
+        }
+        Ok(())
+    }
+}
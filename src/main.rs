@@ -1,5 +1,5 @@
 use log::{error, info};
-use picos::create_pico_service;
+use picos::PicoService;
 
 mod admin;
 
@@ -16,7 +16,7 @@ fn main() -> std::io::Result<()> {
     // No arguments, start the HTTP server
     info!("Starting pico application...");
 
-    let mut pico = match create_pico_service(Some("config.lua".to_string()), None) {
+    let mut pico = match PicoService::builder().build() {
         Ok(service) => service,
         Err(e) => {
             error!("Failed to create pico service: {}", e);
@@ -0,0 +1,328 @@
+pub mod oidc {
+    use std::{collections::HashMap, time::Duration};
+
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use chrono::Utc;
+    use jsonwebtoken::{
+        Algorithm, DecodingKey, Validation, decode, decode_header,
+        jwk::{Jwk, JwkSet},
+    };
+    use log::{debug, warn};
+    use mlua::{FromLua, Lua, Value};
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value as JsonValue;
+    use sha2::{Digest, Sha256};
+    use ureq::Agent;
+
+    use crate::auth::auth::{JwtKeys, new_csrf_token};
+
+    /// How long a login started at the login route can take to come back to the callback
+    pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+    /// OpenID Connect login settings declared with AUTH.OIDC
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct OidcConfig {
+        pub issuer: String,
+        pub client_id: String,
+        pub client_secret: Option<String>,
+        pub scopes: Vec<String>,
+        pub redirect_uri: String, // The callback route's URL, as registered with the IdP
+        pub login_path: String,
+        pub callback_path: String,
+        pub redirect: String, // Where users land after logging in without a next parameter
+    }
+
+    impl FromLua for OidcConfig {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            let conversion_error = |message: &str| mlua::Error::FromLuaConversionError {
+                from: "table",
+                to: "pico::oidc::OidcConfig".to_string(),
+                message: Some(message.to_string()),
+            };
+            let t = match value {
+                Value::Table(t) => t,
+                _ => return Err(conversion_error("expected AUTH.OIDC to be a table")),
+            };
+
+            let issuer: String = t
+                .get::<Option<String>>("ISSUER")?
+                .ok_or_else(|| conversion_error("AUTH.OIDC requires ISSUER"))?;
+            let client_id: String = t
+                .get::<Option<String>>("CLIENT_ID")?
+                .ok_or_else(|| conversion_error("AUTH.OIDC requires CLIENT_ID"))?;
+            // Built from the request's Host header instead, a forged header could send the
+            // code to another site
+            let redirect_uri: String = t
+                .get::<Option<String>>("REDIRECT_URI")?
+                .ok_or_else(|| conversion_error("AUTH.OIDC requires REDIRECT_URI"))?;
+            let mut scopes: Vec<String> =
+                t.get::<Option<Vec<String>>>("SCOPES")?.unwrap_or_else(|| {
+                    vec![
+                        "openid".to_string(),
+                        "email".to_string(),
+                        "profile".to_string(),
+                    ]
+                });
+            if !scopes.iter().any(|scope| scope == "openid") {
+                scopes.insert(0, "openid".to_string());
+            }
+
+            let path = |key: &str, default: &str| -> mlua::Result<String> {
+                Ok(t.get::<Option<String>>(key)?
+                    .unwrap_or_else(|| default.to_string())
+                    .trim_matches('/')
+                    .to_string())
+            };
+
+            Ok(OidcConfig {
+                issuer: issuer.trim_end_matches('/').to_string(),
+                client_id,
+                client_secret: t.get("CLIENT_SECRET")?,
+                scopes,
+                redirect_uri,
+                login_path: path("LOGIN_PATH", "auth/login")?,
+                callback_path: path("CALLBACK_PATH", "auth/callback")?,
+                redirect: t
+                    .get::<Option<String>>("REDIRECT")?
+                    .unwrap_or_else(|| "/".to_string()),
+            })
+        }
+    }
+
+    /// Endpoints published in the issuer's openid-configuration document
+    #[derive(Deserialize, Debug)]
+    struct ProviderMetadata {
+        issuer: String,
+        authorization_endpoint: String,
+        token_endpoint: String,
+        jwks_uri: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct TokenResponse {
+        id_token: String,
+    }
+
+    /// A login waiting for the IdP to redirect back to the callback route. It's kept in a
+    /// signed cookie, so the callback can reach any instance and logins take no memory.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct PendingLogin {
+        state: String,
+        nonce: String,
+        code_verifier: String,
+        next: String,
+        expires_at: i64,
+    }
+
+    impl PendingLogin {
+        /// Encodes the login as a signed cookie value
+        pub fn to_cookie(&self, keys: &JwtKeys) -> String {
+            let json = serde_json::to_string(self).expect("PendingLogin serializes to JSON");
+            keys.sign_value(&URL_SAFE_NO_PAD.encode(json))
+        }
+
+        /// Decodes a cookie value set by to_cookie, if its signature matches
+        pub fn from_cookie(value: &str, keys: &JwtKeys) -> Option<Self> {
+            let encoded = keys.verify_value(value)?;
+            let json = URL_SAFE_NO_PAD.decode(encoded).ok()?;
+            serde_json::from_slice(&json).ok()
+        }
+    }
+
+    /// Runs the authorization code flow with PKCE against an OpenID Connect provider
+    pub struct OidcClient {
+        pub config: OidcConfig,
+        agent: Agent,
+        provider: Option<ProviderMetadata>,
+        jwks: JwkSet,
+    }
+
+    impl OidcClient {
+        pub fn new(config: OidcConfig) -> Self {
+            let agent: Agent = Agent::config_builder()
+                .timeout_global(Some(Duration::from_secs(10)))
+                .build()
+                .into();
+            OidcClient {
+                config,
+                agent,
+                provider: None,
+                jwks: JwkSet { keys: vec![] },
+            }
+        }
+
+        /// Fetches the provider metadata on first use, so pico can start while the IdP is down
+        fn provider(&mut self) -> Result<&ProviderMetadata, String> {
+            if self.provider.is_none() {
+                let url = format!("{}/.well-known/openid-configuration", self.config.issuer);
+                debug!("Fetching OIDC provider metadata from {}", url);
+                let metadata: ProviderMetadata = self
+                    .agent
+                    .get(&url)
+                    .call()
+                    .and_then(|mut r| r.body_mut().read_json())
+                    .map_err(|e| format!("error fetching {}: {}", url, e))?;
+                if metadata.issuer.trim_end_matches('/') != self.config.issuer {
+                    return Err(format!(
+                        "OIDC provider metadata is for issuer {}, expected {}",
+                        metadata.issuer, self.config.issuer
+                    ));
+                }
+                self.provider = Some(metadata);
+            }
+            Ok(self.provider.as_ref().unwrap())
+        }
+
+        /// Starts a login and returns the IdP authorization URL to redirect to, along with
+        /// the pending login that ties the callback to this browser
+        pub fn begin_login(
+            &mut self,
+            next: Option<&str>,
+        ) -> Result<(String, PendingLogin), String> {
+            let state = new_csrf_token();
+            let nonce = new_csrf_token();
+            let code_verifier = new_csrf_token();
+            let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+            let mut location = url::Url::parse(&self.provider()?.authorization_endpoint)
+                .map_err(|e| format!("invalid authorization_endpoint: {}", e))?;
+            location
+                .query_pairs_mut()
+                .append_pair("response_type", "code")
+                .append_pair("client_id", &self.config.client_id)
+                .append_pair("redirect_uri", &self.config.redirect_uri)
+                .append_pair("scope", &self.config.scopes.join(" "))
+                .append_pair("state", &state)
+                .append_pair("nonce", &nonce)
+                .append_pair("code_challenge", &code_challenge)
+                .append_pair("code_challenge_method", "S256");
+
+            // Only local paths are followed after login, anything else could be an open redirect
+            let next = match next {
+                Some(n) if n.starts_with('/') && !n.starts_with("//") && !n.starts_with("/\\") => {
+                    n.to_string()
+                }
+                _ => self.config.redirect.clone(),
+            };
+            let login = PendingLogin {
+                state,
+                nonce,
+                code_verifier,
+                next,
+                expires_at: Utc::now().timestamp() + LOGIN_TIMEOUT.as_secs() as i64,
+            };
+            Ok((location.to_string(), login))
+        }
+
+        /// Finishes a login from the parameters the IdP redirected back with. Exchanges the
+        /// code for an ID token and returns its validated claims and where to send the user.
+        pub fn complete_login(
+            &mut self,
+            params: &HashMap<String, String>,
+            login: Option<PendingLogin>,
+        ) -> Result<(JsonValue, String), String> {
+            if let Some(error) = params.get("error") {
+                return Err(format!(
+                    "IdP returned {}: {}",
+                    error,
+                    params
+                        .get("error_description")
+                        .map(String::as_str)
+                        .unwrap_or("")
+                ));
+            }
+            let state = params.get("state").ok_or("missing state parameter")?;
+            let login = login.ok_or("missing or invalid login cookie")?;
+            if login.state != *state {
+                return Err("state parameter does not match the login cookie".to_string());
+            }
+            if login.expires_at < Utc::now().timestamp() {
+                return Err("login expired".to_string());
+            }
+            let code = params.get("code").ok_or("missing code parameter")?;
+
+            let token_endpoint = self.provider()?.token_endpoint.clone();
+            let mut form = vec![
+                ("grant_type", "authorization_code"),
+                ("code", code.as_str()),
+                ("redirect_uri", self.config.redirect_uri.as_str()),
+                ("client_id", self.config.client_id.as_str()),
+                ("code_verifier", login.code_verifier.as_str()),
+            ];
+            if let Some(secret) = &self.config.client_secret {
+                form.push(("client_secret", secret.as_str()));
+            }
+            let tokens: TokenResponse = self
+                .agent
+                .post(&token_endpoint)
+                .send_form(form)
+                .and_then(|mut r| r.body_mut().read_json())
+                .map_err(|e| format!("error exchanging code at {}: {}", token_endpoint, e))?;
+
+            let claims = self.validate_id_token(&tokens.id_token)?;
+            if claims.get("nonce").and_then(JsonValue::as_str) != Some(login.nonce.as_str()) {
+                return Err("ID token nonce does not match the login".to_string());
+            }
+            Ok((claims, login.next))
+        }
+
+        /// Verifies an ID token's signature against the issuer's JWKS, and its iss, aud and exp
+        fn validate_id_token(&mut self, id_token: &str) -> Result<JsonValue, String> {
+            let header = decode_header(id_token).map_err(|e| format!("invalid ID token: {}", e))?;
+            if matches!(
+                header.alg,
+                Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+            ) {
+                return Err(format!("unsupported ID token algorithm {:?}", header.alg));
+            }
+
+            let key = match self.find_key(header.kid.as_deref()) {
+                Some(key) => key,
+                None => {
+                    // The IdP may have rotated its keys since they were last fetched
+                    self.refresh_jwks()?;
+                    self.find_key(header.kid.as_deref())
+                        .ok_or("no key in the issuer's JWKS matches the ID token")?
+                }
+            };
+            let decoding_key =
+                DecodingKey::from_jwk(&key).map_err(|e| format!("invalid JWK: {}", e))?;
+
+            let mut validation = Validation::new(header.alg);
+            validation.set_issuer(&[&self.config.issuer, &format!("{}/", self.config.issuer)]);
+            validation.set_audience(&[&self.config.client_id]);
+            decode::<JsonValue>(id_token, &decoding_key, &validation)
+                .map(|token| token.claims)
+                .map_err(|e| format!("invalid ID token: {}", e))
+        }
+
+        fn find_key(&self, kid: Option<&str>) -> Option<Jwk> {
+            match kid {
+                Some(kid) => self.jwks.find(kid).cloned(),
+                // Without a kid the token can only be checked against a lone key
+                None if self.jwks.keys.len() == 1 => self.jwks.keys.first().cloned(),
+                None => None,
+            }
+        }
+
+        fn refresh_jwks(&mut self) -> Result<(), String> {
+            let jwks_uri = self.provider()?.jwks_uri.clone();
+            debug!("Fetching OIDC signing keys from {}", jwks_uri);
+            match self
+                .agent
+                .get(&jwks_uri)
+                .call()
+                .and_then(|mut r| r.body_mut().read_json::<JwkSet>())
+            {
+                Ok(jwks) => {
+                    self.jwks = jwks;
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!("Error fetching {}: {}", jwks_uri, e);
+                    Err(format!("error fetching {}: {}", jwks_uri, e))
+                }
+            }
+        }
+    }
+}
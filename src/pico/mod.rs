@@ -1 +1,613 @@
-pub mod pico {}
+pub mod pico {
+    use std::{cell::RefCell, collections::HashMap, time::Duration};
+
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use hmac::{Hmac, Mac};
+    use log::{error, info, warn};
+    use mlua::{FromLua, Lua, LuaSerdeExt, Table, Value, Variadic};
+    use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+    use rand::RngCore;
+    use serde_json::Value as JsonValue;
+    use sha2::{Digest, Sha256};
+    use ureq::Agent;
+    use url::Url;
+
+    use crate::{
+        http::http::ResponseCode,
+        jobs::jobs::{JobNames, RunAt, enqueue},
+        lua_duration,
+        sandbox::sandbox::{handler_timeout, time_remaining},
+        sql::sql::{RlsConfig, SQL},
+    };
+
+    /// Characters pico.url.encode leaves alone, the unreserved set of RFC 3986
+    const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+        .remove(b'-')
+        .remove(b'_')
+        .remove(b'.')
+        .remove(b'~');
+
+    /// Largest buffer pico.crypto.random_bytes hands out
+    const MAX_RANDOM_BYTES: usize = 1024 * 1024;
+
+    /// Timeout of a pico.http.request call without its own timeout or LUA.HTTP.TIMEOUT
+    const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Settings for the pico global declared with LUA
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct LuaConfig {
+        pub sql_query: bool, // Whether handlers can run their own queries with pico.sql.query
+        pub http: Option<HttpClientConfig>, // Outbound HTTP, disabled unless declared
+    }
+
+    /// Hosts pico.http.request may call, declared with LUA.HTTP
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct HttpClientConfig {
+        pub hosts: Vec<String>, // Like 'api.stripe.com', '*.example.com' or '127.0.0.1:8081'
+        pub timeout: Duration,  // Default timeout of a call
+    }
+
+    impl FromLua for LuaConfig {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            let conversion_error = |message: &str| mlua::Error::FromLuaConversionError {
+                from: "table",
+                to: "pico::pico::LuaConfig".to_string(),
+                message: Some(message.to_string()),
+            };
+            let t = match value {
+                Value::Table(t) => t,
+                _ => return Err(conversion_error("expected LUA to be a table")),
+            };
+
+            let http = match t.get::<Option<Table>>("HTTP")? {
+                Some(http) => {
+                    let hosts: Vec<String> = http
+                        .get::<Option<Vec<String>>>("HOSTS")?
+                        .filter(|hosts| !hosts.is_empty())
+                        .ok_or_else(|| conversion_error("LUA HTTP requires HOSTS"))?
+                        .into_iter()
+                        .map(|host| host.to_lowercase())
+                        .collect();
+                    let timeout = match http.get::<Option<Value>>("TIMEOUT")? {
+                        Some(v) => lua_duration(&v).filter(|d| !d.is_zero()).ok_or_else(|| {
+                            conversion_error(
+                                "invalid LUA HTTP TIMEOUT, expected seconds or a duration like '5s'",
+                            )
+                        })?,
+                        None => DEFAULT_HTTP_TIMEOUT,
+                    };
+                    Some(HttpClientConfig { hosts, timeout })
+                }
+                None => None,
+            };
+
+            Ok(LuaConfig {
+                sql_query: t.get::<Option<bool>>("SQL_QUERY")?.unwrap_or(false),
+                http,
+            })
+        }
+    }
+
+    /// Adds the pico global with helpers for Lua handlers. Runs before config.lua is loaded,
+    /// so the config can use it too, for example to read settings with pico.env.
+    pub fn register_pico_module(lua: &Lua) -> mlua::Result<()> {
+        let pico = lua.create_table()?;
+        pico.set("json", json_module(lua)?)?;
+        pico.set("log", log_module(lua)?)?;
+        pico.set("crypto", crypto_module(lua)?)?;
+        pico.set("base64", base64_module(lua)?)?;
+        pico.set("url", url_module(lua)?)?;
+        pico.set("sql", unavailable_sql_module(lua)?)?;
+        pico.set("enqueue", unavailable_enqueue(lua)?)?;
+        pico.set("http", http_module(lua)?)?;
+        pico.set(
+            "uuid",
+            lua.create_function(|_, ()| {
+                let mut bytes = [0u8; 16];
+                rand::thread_rng().fill_bytes(&mut bytes);
+                Ok(uuid::Builder::from_random_bytes(bytes)
+                    .into_uuid()
+                    .to_string())
+            })?,
+        )?;
+        pico.set(
+            "now",
+            lua.create_function(|_, ()| Ok(chrono::Utc::now().to_rfc3339()))?,
+        )?;
+        pico.set(
+            "env",
+            lua.create_function(|_, name: String| Ok(std::env::var(name).ok()))?,
+        )?;
+        lua.globals().set("pico", pico)
+    }
+
+    fn json_module(lua: &Lua) -> mlua::Result<Table> {
+        let json = lua.create_table()?;
+        json.set(
+            "encode",
+            lua.create_function(|lua, value: Value| {
+                let value: JsonValue = lua.from_value(value)?;
+                Ok(value.to_string())
+            })?,
+        )?;
+        json.set(
+            "decode",
+            lua.create_function(|lua, text: String| {
+                let value: JsonValue = serde_json::from_str(&text)
+                    .map_err(|e| mlua::Error::RuntimeError(format!("pico.json.decode: {}", e)))?;
+                lua.to_value(&value)
+            })?,
+        )?;
+        json.set("null", lua.null())?;
+        Ok(json)
+    }
+
+    /// Joins the arguments of a pico.log call like print does
+    fn log_message(values: Variadic<Value>) -> String {
+        values
+            .iter()
+            .map(|v| v.to_string().unwrap_or_else(|_| format!("{:?}", v)))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    fn log_module(lua: &Lua) -> mlua::Result<Table> {
+        let log = lua.create_table()?;
+        log.set(
+            "info",
+            lua.create_function(|_, values: Variadic<Value>| {
+                info!(target: "pico::lua", "{}", log_message(values));
+                Ok(())
+            })?,
+        )?;
+        log.set(
+            "warn",
+            lua.create_function(|_, values: Variadic<Value>| {
+                warn!(target: "pico::lua", "{}", log_message(values));
+                Ok(())
+            })?,
+        )?;
+        log.set(
+            "error",
+            lua.create_function(|_, values: Variadic<Value>| {
+                error!(target: "pico::lua", "{}", log_message(values));
+                Ok(())
+            })?,
+        )?;
+        Ok(log)
+    }
+
+    fn crypto_module(lua: &Lua) -> mlua::Result<Table> {
+        let crypto = lua.create_table()?;
+        crypto.set(
+            "sha256",
+            lua.create_function(|_, data: mlua::String| {
+                Ok(to_hex(&Sha256::digest(data.as_bytes())))
+            })?,
+        )?;
+        crypto.set(
+            "hmac",
+            lua.create_function(|_, (key, data): (mlua::String, mlua::String)| {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&key.as_bytes())
+                    .map_err(|e| mlua::Error::RuntimeError(format!("pico.crypto.hmac: {}", e)))?;
+                mac.update(&data.as_bytes());
+                Ok(to_hex(&mac.finalize().into_bytes()))
+            })?,
+        )?;
+        crypto.set(
+            "random_bytes",
+            lua.create_function(|lua, length: usize| {
+                if length > MAX_RANDOM_BYTES {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "pico.crypto.random_bytes: at most {} bytes can be requested",
+                        MAX_RANDOM_BYTES
+                    )));
+                }
+                let mut bytes = vec![0u8; length];
+                rand::thread_rng().fill_bytes(&mut bytes);
+                lua.create_string(&bytes)
+            })?,
+        )?;
+        Ok(crypto)
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn base64_module(lua: &Lua) -> mlua::Result<Table> {
+        let base64 = lua.create_table()?;
+        base64.set(
+            "encode",
+            lua.create_function(|_, data: mlua::String| Ok(STANDARD.encode(data.as_bytes())))?,
+        )?;
+        base64.set(
+            "decode",
+            lua.create_function(|lua, text: String| {
+                let bytes = STANDARD
+                    .decode(text.trim())
+                    .map_err(|e| mlua::Error::RuntimeError(format!("pico.base64.decode: {}", e)))?;
+                lua.create_string(&bytes)
+            })?,
+        )?;
+        Ok(base64)
+    }
+
+    fn url_module(lua: &Lua) -> mlua::Result<Table> {
+        let url = lua.create_table()?;
+        url.set(
+            "encode",
+            lua.create_function(|_, text: String| {
+                Ok(utf8_percent_encode(&text, URL_COMPONENT).to_string())
+            })?,
+        )?;
+        url.set(
+            "decode",
+            lua.create_function(|lua, text: String| {
+                lua.create_string(percent_decode_str(&text).collect::<Vec<u8>>())
+            })?,
+        )?;
+        Ok(url)
+    }
+
+    /// Request state pico.sql runs its queries with
+    pub struct SqlContext<'a> {
+        pub rls: Option<&'a RlsConfig>,
+        pub claims: Option<&'a JsonValue>,
+    }
+
+    /// Makes pico.sql and pico.enqueue available to the Lua code run by `f`. Their queries
+    /// run in the request's transaction, which is started by the first one.
+    pub fn with_request_sql<R>(
+        lua: &Lua,
+        sql: &mut SQL,
+        context: SqlContext,
+        f: impl FnOnce() -> R,
+    ) -> R {
+        let sql = RefCell::new(sql);
+        let mut f = Some(f);
+        let mut result = None;
+        let scoped = lua.scope(|scope| {
+            let module = lua.create_table()?;
+            module.set(
+                "call",
+                scope.create_function(|lua, (name, params): (String, Option<Table>)| {
+                    let input: HashMap<String, JsonValue> = match params {
+                        Some(params) => params
+                            .pairs::<String, Value>()
+                            .map(|pair| {
+                                let (key, value) = pair?;
+                                Ok((key, lua.from_value(value)?))
+                            })
+                            .collect::<mlua::Result<_>>()?,
+                        None => HashMap::new(),
+                    };
+                    let mut sql = sql.borrow_mut();
+                    let function = sql.functions.get(&name).ok_or_else(|| {
+                        sql_error(format!("pico.sql.call: unknown function {}", name))
+                    })?;
+                    if let Some(param) =
+                        function.parameters.iter().find(|p| !input.contains_key(*p))
+                    {
+                        return Err(sql_error(format!(
+                            "pico.sql.call: {} is missing parameter {}",
+                            name, param
+                        )));
+                    }
+                    sql.begin_request(context.rls, context.claims)
+                        .map_err(|_| {
+                            sql_error("pico.sql.call: error starting the request transaction")
+                        })?;
+
+                    let sql = &mut **sql;
+                    let result = sql.functions[&name].execute(&mut sql.connection, input);
+                    match result {
+                        Ok(JsonValue::Null) => Ok(Value::Nil),
+                        Ok(value) => lua.to_value(&value),
+                        // Parameters that don't convert to their SQL types never reach Postgres
+                        Err(ResponseCode::BadRequest) => Err(sql_error(format!(
+                            "pico.sql.call: parameters of {} don't match their SQL types",
+                            name
+                        ))),
+                        Err(_) => {
+                            sql.fail_request();
+                            Err(sql_error(format!(
+                                "pico.sql.call: {} failed, see the server log for details",
+                                name
+                            )))
+                        }
+                    }
+                })?,
+            )?;
+            module.set(
+                "query",
+                scope.create_function(|lua, (text, params): (String, Option<Table>)| {
+                    let enabled = lua.app_data_ref::<LuaConfig>().is_some_and(|c| c.sql_query);
+                    if !enabled {
+                        return Err(sql_error(
+                            "pico.sql.query is disabled, enable it with LUA = { SQL_QUERY = true }",
+                        ));
+                    }
+                    // Parameters are positional, a nil or pico.json.null one binds NULL
+                    let mut values: Vec<JsonValue> = vec![];
+                    if let Some(params) = params {
+                        for pair in params.pairs::<usize, Value>() {
+                            let (position, value) = pair?;
+                            if position == 0 {
+                                return Err(sql_error(
+                                    "pico.sql.query: expected params to be a list",
+                                ));
+                            }
+                            if values.len() < position {
+                                values.resize(position, JsonValue::Null);
+                            }
+                            values[position - 1] = lua.from_value(value)?;
+                        }
+                    }
+
+                    let mut sql = sql.borrow_mut();
+                    sql.begin_request(context.rls, context.claims)
+                        .map_err(|_| {
+                            sql_error("pico.sql.query: error starting the request transaction")
+                        })?;
+                    match sql.query_json(&text, &values) {
+                        Ok(rows) => lua.to_value(&rows),
+                        Err(e) => Err(sql_error(format!("pico.sql.query: {}", e))),
+                    }
+                })?,
+            )?;
+
+            // Jobs are enqueued in the request's transaction, so they're dropped when it
+            // rolls back. They're written as the connecting user, whatever the RLS role.
+            let enqueue_job = scope.create_function(
+                |lua, (name, payload, options): (String, Value, Option<Table>)| {
+                    let declared = lua
+                        .app_data_ref::<JobNames>()
+                        .is_some_and(|names| names.0.contains(&name));
+                    if !declared {
+                        return Err(sql_error(format!(
+                            "pico.enqueue: unknown job {}, declare it in JOBS",
+                            name
+                        )));
+                    }
+                    let payload: JsonValue = match payload {
+                        Value::Nil => JsonValue::Object(Default::default()),
+                        payload => lua.from_value(payload)?,
+                    };
+                    let mut run_at = None;
+                    let mut max_attempts = None;
+                    if let Some(options) = options {
+                        run_at = match options.get::<Value>("run_at")? {
+                            Value::Nil => None,
+                            value => match (lua_duration(&value), value) {
+                                (Some(delay), _) => Some(RunAt::In(delay)),
+                                (None, Value::String(at)) => {
+                                    Some(RunAt::At(at.to_str()?.to_string()))
+                                }
+                                _ => {
+                                    return Err(sql_error(
+                                        "pico.enqueue: expected run_at to be a timestamp or a delay",
+                                    ));
+                                }
+                            },
+                        };
+                        max_attempts = options.get::<Option<i32>>("max_attempts")?;
+                        if max_attempts.is_some_and(|n| n < 1) {
+                            return Err(sql_error(
+                                "pico.enqueue: max_attempts must be at least 1",
+                            ));
+                        }
+                    }
+
+                    let mut sql = sql.borrow_mut();
+                    sql.begin_request(context.rls, context.claims)
+                        .map_err(|_| {
+                            sql_error("pico.enqueue: error starting the request transaction")
+                        })?;
+                    let id = sql.as_owner(|client| {
+                        enqueue(client, &name, &payload, run_at.as_ref(), max_attempts)
+                    });
+                    id.map_err(|e| {
+                        sql.fail_request();
+                        sql_error(format!("pico.enqueue: {}", e))
+                    })
+                },
+            )?;
+
+            let pico: Table = lua.globals().get("pico")?;
+            pico.set("sql", module)?;
+            pico.set("enqueue", enqueue_job)?;
+            result = f.take().map(|f| f());
+            pico.set("sql", unavailable_sql_module(lua)?)?;
+            pico.set("enqueue", unavailable_enqueue(lua)?)
+        });
+        if let Err(e) = scoped {
+            error!("Error scoping pico.sql to the handler: {}", e);
+        }
+        match f.take() {
+            // pico.sql couldn't be installed, the handler still runs without it
+            Some(f) => f(),
+            None => result.expect("the handler ran in the scope"),
+        }
+    }
+
+    fn sql_error(message: impl Into<String>) -> mlua::Error {
+        mlua::Error::RuntimeError(message.into())
+    }
+
+    /// pico.sql outside request handlers, where there's no request transaction to run in
+    fn unavailable_sql_module(lua: &Lua) -> mlua::Result<Table> {
+        let sql = lua.create_table()?;
+        for name in ["call", "query"] {
+            sql.set(
+                name,
+                lua.create_function(move |_, _: Variadic<Value>| -> mlua::Result<()> {
+                    Err(sql_error(format!(
+                        "pico.sql.{} can only be used while handling a request",
+                        name
+                    )))
+                })?,
+            )?;
+        }
+        Ok(sql)
+    }
+
+    /// pico.enqueue outside request handlers, where there's no transaction to enqueue in
+    fn unavailable_enqueue(lua: &Lua) -> mlua::Result<mlua::Function> {
+        lua.create_function(|_, _: Variadic<Value>| -> mlua::Result<()> {
+            Err(sql_error(
+                "pico.enqueue can only be used while handling a request",
+            ))
+        })
+    }
+
+    fn http_module(lua: &Lua) -> mlua::Result<Table> {
+        let agent: Agent = Agent::config_builder()
+            .http_status_as_error(false)
+            // A redirect could lead outside LUA.HTTP.HOSTS, so handlers follow them themselves
+            .max_redirects(0)
+            .build()
+            .into();
+        let http = lua.create_table()?;
+        http.set(
+            "request",
+            lua.create_function(move |lua, options: Table| http_request(lua, &agent, options))?,
+        )?;
+        Ok(http)
+    }
+
+    fn http_error(message: String) -> mlua::Error {
+        mlua::Error::RuntimeError(format!("pico.http.request: {}", message))
+    }
+
+    fn http_request(lua: &Lua, agent: &Agent, options: Table) -> mlua::Result<Table> {
+        let config = lua
+            .app_data_ref::<LuaConfig>()
+            .and_then(|c| c.http.clone())
+            .ok_or_else(|| {
+                http_error(
+                    "disabled, allow hosts with LUA = { HTTP = { HOSTS = { ... } } }".to_string(),
+                )
+            })?;
+
+        let url = options
+            .get::<Option<String>>("url")?
+            .ok_or_else(|| http_error("url is required".to_string()))?;
+        let parsed =
+            Url::parse(&url).map_err(|e| http_error(format!("invalid url {}: {}", url, e)))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(http_error(format!("{} is not an http or https url", url)));
+        }
+        if !host_allowed(&config.hosts, &parsed) {
+            return Err(http_error(format!(
+                "{} is not in LUA.HTTP.HOSTS",
+                parsed.host_str().unwrap_or(&url)
+            )));
+        }
+
+        let method = options
+            .get::<Option<String>>("method")?
+            .unwrap_or("GET".to_string())
+            .to_uppercase();
+        let mut builder = ureq::http::Request::builder()
+            .method(method.as_str())
+            .uri(&url);
+        let mut content_type = false;
+        if let Some(headers) = options.get::<Option<Table>>("headers")? {
+            for pair in headers.pairs::<String, String>() {
+                let (name, value) = pair?;
+                content_type |= name.eq_ignore_ascii_case("content-type");
+                builder = builder.header(name, value);
+            }
+        }
+        // Tables are sent as JSON, strings as they are
+        let body = match options.get::<Value>("body")? {
+            Value::Nil => vec![],
+            Value::String(s) => s.as_bytes().to_vec(),
+            v => {
+                if !content_type {
+                    builder = builder.header("Content-Type", "application/json");
+                }
+                lua.from_value::<JsonValue>(v)?.to_string().into_bytes()
+            }
+        };
+        let request = builder
+            .body(body)
+            .map_err(|e| http_error(format!("invalid request to {}: {}", url, e)))?;
+
+        // The sandbox can't interrupt a call while it waits on the network, so the call
+        // can't outlast the handler's SANDBOX.TIMEOUT
+        let mut timeout = match options.get::<Option<Value>>("timeout")? {
+            Some(v) => lua_duration(&v).filter(|d| !d.is_zero()).ok_or_else(|| {
+                http_error("invalid timeout, expected seconds or a duration like '5s'".to_string())
+            })?,
+            None => config.timeout,
+        };
+        let remaining = time_remaining(lua);
+        let capped = remaining.is_some_and(|remaining| remaining <= timeout);
+        if let Some(remaining) = remaining {
+            timeout = timeout.min(remaining);
+        }
+        if timeout.is_zero() {
+            return Err(handler_timeout(lua));
+        }
+        let failed = |e: ureq::Error| match e {
+            ureq::Error::Timeout(_) if capped => handler_timeout(lua),
+            e => http_error(format!("{} {}: {}", method, url, e)),
+        };
+
+        let request = agent
+            .configure_request(request)
+            .timeout_global(Some(timeout))
+            .build();
+        let mut response = agent.run(request).map_err(failed)?;
+        let bytes = response.body_mut().read_to_vec().map_err(failed)?;
+
+        let result = lua.create_table()?;
+        result.set("status", response.status().as_u16())?;
+        let headers = lua.create_table()?;
+        for name in response.headers().keys() {
+            let values: Vec<&str> = response
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .collect();
+            headers.set(name.as_str(), values.join(", "))?;
+        }
+        result.set("headers", headers)?;
+        // JSON responses are decoded, anything else is handed over as a string
+        let json = response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("json"));
+        let body = match serde_json::from_slice::<JsonValue>(&bytes) {
+            Ok(value) if json => lua.to_value(&value)?,
+            _ => Value::String(lua.create_string(&bytes)?),
+        };
+        result.set("body", body)?;
+        Ok(result)
+    }
+
+    /// Whether LUA.HTTP.HOSTS lets handlers call the url. Hosts listed without a port
+    /// allow any port, and '*.example.com' allows every subdomain of example.com.
+    fn host_allowed(hosts: &[String], url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+        let port = url.port_or_known_default();
+        hosts.iter().any(|allowed| {
+            let (allowed_host, allowed_port) = match allowed.rsplit_once(':') {
+                Some((h, p)) if p.parse::<u16>().is_ok() => (h, p.parse::<u16>().ok()),
+                _ => (allowed.as_str(), None),
+            };
+            let host_matches = match allowed_host.strip_prefix("*.") {
+                Some(domain) => host.ends_with(&format!(".{}", domain)),
+                None => host == allowed_host,
+            };
+            host_matches && allowed_port.is_none_or(|p| Some(p) == port)
+        })
+    }
+}
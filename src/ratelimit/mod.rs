@@ -0,0 +1,277 @@
+pub mod ratelimit {
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    use mlua::{FromLua, Function, Lua, Value};
+    use postgres::Client;
+
+    use crate::lua_duration;
+
+    /// Buckets kept in memory before idle ones are dropped
+    const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+    /// How often expired buckets are deleted from pico.rate_limits
+    const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Request limits declared with RATE_LIMIT on a route or route group
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct RateLimit {
+        pub requests: u32, // Bucket capacity, the requests allowed in a burst
+        pub per: Duration, // Time it takes for an empty bucket to fill up again
+        pub key: RateLimitKey,
+        pub store: RateLimitStore,
+    }
+
+    /// What requests share a bucket
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum RateLimitKey {
+        /// The client IP address
+        Ip,
+        /// A JWT claim, like `jwt.userId`. Requests without the claim fall back to the IP.
+        Claim(String),
+        /// A Lua function receiving the request and returning the key, or nil to skip the limit
+        Function(Function),
+    }
+
+    /// Where buckets are kept
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum RateLimitStore {
+        /// In memory, per pico instance
+        Memory,
+        /// In the pico.rate_limits table, shared by every instance using the database
+        Postgres,
+    }
+
+    impl FromLua for RateLimit {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            let conversion_error = |message: String| mlua::Error::FromLuaConversionError {
+                from: "table",
+                to: "pico::ratelimit::RateLimit".to_string(),
+                message: Some(message),
+            };
+            let t = match value {
+                Value::Table(t) => t,
+                _ => {
+                    return Err(conversion_error(
+                        "expected RATE_LIMIT to be a table".to_string(),
+                    ));
+                }
+            };
+
+            let requests: u32 = t
+                .get::<Option<u32>>("REQUESTS")?
+                .filter(|r| *r > 0)
+                .ok_or_else(|| {
+                    conversion_error("RATE_LIMIT requires REQUESTS greater than 0".to_string())
+                })?;
+            let per = match t.get::<Option<Value>>("PER")? {
+                Some(v) => lua_duration(&v).filter(|d| !d.is_zero()).ok_or_else(|| {
+                    conversion_error(
+                        "invalid RATE_LIMIT PER, expected seconds or a duration like '1m'"
+                            .to_string(),
+                    )
+                })?,
+                None => Duration::from_secs(60),
+            };
+            let key = match t.get::<Option<Value>>("KEY")? {
+                None => RateLimitKey::Ip,
+                Some(Value::Function(f)) => RateLimitKey::Function(f),
+                Some(Value::String(s)) => match s.to_str()?.as_ref() {
+                    "ip" => RateLimitKey::Ip,
+                    other => match other.strip_prefix("jwt.") {
+                        Some(claim) if !claim.is_empty() => RateLimitKey::Claim(claim.to_string()),
+                        _ => {
+                            return Err(conversion_error(format!(
+                                "unknown RATE_LIMIT KEY {}, expected 'ip', 'jwt.<claim>' or a function",
+                                other
+                            )));
+                        }
+                    },
+                },
+                Some(other) => {
+                    return Err(conversion_error(format!(
+                        "invalid RATE_LIMIT KEY {}, expected 'ip', 'jwt.<claim>' or a function",
+                        other.type_name()
+                    )));
+                }
+            };
+            let store = match t.get::<Option<String>>("STORE")?.as_deref() {
+                None | Some("memory") => RateLimitStore::Memory,
+                Some("postgres") => RateLimitStore::Postgres,
+                Some(other) => {
+                    return Err(conversion_error(format!(
+                        "unknown RATE_LIMIT STORE {}, expected 'memory' or 'postgres'",
+                        other
+                    )));
+                }
+            };
+
+            Ok(RateLimit {
+                requests,
+                per,
+                key,
+                store,
+            })
+        }
+    }
+
+    /// The outcome of taking a token from a bucket
+    #[derive(Debug, PartialEq)]
+    pub struct RateLimitDecision {
+        pub allowed: bool,
+        pub limit: u32,
+        pub remaining: u32,
+        pub reset: u64,       // Seconds until the bucket is full again
+        pub retry_after: u64, // Seconds until the next request is allowed
+    }
+
+    impl RateLimit {
+        /// Tokens added back to a bucket per second
+        fn refill_rate(&self) -> f64 {
+            self.requests as f64 / self.per.as_secs_f64()
+        }
+
+        fn decision(&self, allowed: bool, tokens: f64) -> RateLimitDecision {
+            let rate = self.refill_rate();
+            RateLimitDecision {
+                allowed,
+                limit: self.requests,
+                remaining: tokens.max(0.0).floor() as u32,
+                reset: ((self.requests as f64 - tokens) / rate).max(0.0).ceil() as u64,
+                retry_after: ((1.0 - tokens) / rate).max(0.0).ceil() as u64,
+            }
+        }
+    }
+
+    impl RateLimitDecision {
+        /// Response headers describing the bucket, sent with every response of a limited route
+        pub fn headers(&self) -> Vec<(String, String)> {
+            let mut headers = vec![
+                ("RateLimit-Limit".to_string(), self.limit.to_string()),
+                (
+                    "RateLimit-Remaining".to_string(),
+                    self.remaining.to_string(),
+                ),
+                ("RateLimit-Reset".to_string(), self.reset.to_string()),
+            ];
+            if !self.allowed {
+                headers.push(("Retry-After".to_string(), self.retry_after.to_string()));
+            }
+            headers
+        }
+    }
+
+    struct Bucket {
+        tokens: f64,
+        updated_at: Instant,
+        full_at: Instant, // When the bucket refills completely and can be forgotten
+    }
+
+    /// Token buckets kept in memory
+    #[derive(Default)]
+    pub struct RateLimiter {
+        buckets: HashMap<String, Bucket>,
+        pruned_at: Option<Instant>, // When pico.rate_limits was last pruned by this instance
+    }
+
+    impl RateLimiter {
+        /// Takes a token from the bucket for the key, if one is left
+        pub fn check(&mut self, limit: &RateLimit, key: &str) -> RateLimitDecision {
+            let now = Instant::now();
+            if self.buckets.len() >= MAX_MEMORY_BUCKETS {
+                self.buckets.retain(|_, bucket| bucket.full_at > now);
+            }
+            if self.buckets.len() >= MAX_MEMORY_BUCKETS {
+                // Every bucket is still refilling, so forget the least recently used tenth.
+                // Their keys start over with a full bucket.
+                let mut updated: Vec<Instant> =
+                    self.buckets.values().map(|b| b.updated_at).collect();
+                let evicted = self.buckets.len() - MAX_MEMORY_BUCKETS * 9 / 10;
+                let cutoff = *updated.select_nth_unstable(evicted - 1).1;
+                self.buckets.retain(|_, bucket| bucket.updated_at > cutoff);
+            }
+
+            let capacity = limit.requests as f64;
+            let rate = limit.refill_rate();
+            let bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+                tokens: capacity,
+                updated_at: now,
+                full_at: now,
+            });
+            let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+            bucket.updated_at = now;
+
+            let allowed = bucket.tokens >= 1.0;
+            if allowed {
+                bucket.tokens -= 1.0;
+            }
+            bucket.full_at = now + Duration::from_secs_f64((capacity - bucket.tokens) / rate);
+            limit.decision(allowed, bucket.tokens)
+        }
+
+        /// Deletes the pico.rate_limits buckets that have refilled, at most once per
+        /// PRUNE_INTERVAL, so keys that stop making requests don't stay in the table
+        pub fn prune_postgres(&mut self, client: &mut Client) -> Result<(), String> {
+            if self
+                .pruned_at
+                .is_some_and(|pruned_at| pruned_at.elapsed() < PRUNE_INTERVAL)
+            {
+                return Ok(());
+            }
+            self.pruned_at = Some(Instant::now());
+            client
+                .execute("DELETE FROM pico.rate_limits WHERE expires_at < now()", &[])
+                .map(|_| ())
+                .map_err(|e| format!("error pruning pico.rate_limits: {}", e))
+        }
+    }
+
+    /// Creates the pico.rate_limits table used by RATE_LIMIT STORE = 'postgres'
+    pub fn initialize_rate_limits(client: &mut Client) -> Result<(), String> {
+        client
+            .batch_execute(
+                "CREATE SCHEMA IF NOT EXISTS pico;
+                CREATE UNLOGGED TABLE IF NOT EXISTS pico.rate_limits(
+                    key TEXT PRIMARY KEY,
+                    tokens DOUBLE PRECISION NOT NULL,
+                    allowed BOOLEAN NOT NULL,
+                    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                    expires_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                );",
+            )
+            .map_err(|e| format!("error creating pico.rate_limits table: {}", e))
+    }
+
+    /// Takes a token from a bucket stored in Postgres. The bucket is refilled and updated
+    /// in a single statement, so the row lock keeps concurrent instances from racing.
+    /// A bucket left alone for PER is full again, so it expires then.
+    pub fn check_postgres(
+        client: &mut Client,
+        limit: &RateLimit,
+        key: &str,
+    ) -> Result<RateLimitDecision, String> {
+        let capacity = limit.requests as f64;
+        let rate = limit.refill_rate();
+        let row = client
+            .query_one(
+                "INSERT INTO pico.rate_limits AS r(key, tokens, allowed, updated_at, expires_at)
+                VALUES ($1, $2::float8 - 1, true, now(), now() + make_interval(secs => $4))
+                ON CONFLICT (key) DO UPDATE SET
+                    tokens = CASE
+                        WHEN LEAST($2, r.tokens + EXTRACT(EPOCH FROM now() - r.updated_at)::float8 * $3) >= 1
+                        THEN LEAST($2, r.tokens + EXTRACT(EPOCH FROM now() - r.updated_at)::float8 * $3) - 1
+                        ELSE LEAST($2, r.tokens + EXTRACT(EPOCH FROM now() - r.updated_at)::float8 * $3)
+                    END,
+                    allowed = LEAST($2, r.tokens + EXTRACT(EPOCH FROM now() - r.updated_at)::float8 * $3) >= 1,
+                    updated_at = now(),
+                    expires_at = now() + make_interval(secs => $4)
+                RETURNING r.allowed, r.tokens",
+                &[&key, &capacity, &rate, &limit.per.as_secs_f64()],
+            )
+            .map_err(|e| format!("error checking rate limit: {}", e))?;
+        Ok(limit.decision(row.get(0), row.get(1)))
+    }
+}
@@ -5,11 +5,15 @@ pub mod route {
     use mlua::{FromLua, Function, Lua, Value};
     use serde::{Deserialize, Serialize};
 
-    use crate::html::html::View;
+    use crate::{
+        auth::auth::AuthGuard, cors::cors::CorsConfig, handler::handler::RustHooks,
+        html::html::View, ratelimit::ratelimit::RateLimit,
+    };
 
     #[derive(Debug, PartialEq)]
     pub struct Route {
         pub definitions: HashMap<Method, RouteHandler>,
+        pub trailing_slash: bool, // Whether the route was declared with a trailing slash, e.g. 'login/'
     }
 
     #[derive(Debug, PartialEq)]
@@ -19,14 +23,94 @@ pub mod route {
         pub set_jwt: Option<Function>,         // A lua function that sets the JWT for a user
         pub pre_process: Option<Function>, // A lua function that transforms the data for a request
         pub post_process: Option<Function>, // A lua function that transforms the data from a request
+        pub before: Vec<Function>,          // Group middleware run in order before PREPROCESS
+        pub after: Vec<Function>,           // Group middleware run in order after POSTPROCESS
+        pub auth: Option<AuthGuard>,        // Authentication requirements for the handler
+        pub csrf: bool, // Check CSRF tokens on unsafe methods from cookie sessions
+        pub rate_limit: Option<RateLimit>, // Requests allowed per client for the handler
+        pub cors: Option<CorsConfig>, // Origins allowed to call the handler from a browser
+        pub rust: RustHooks, // Handlers registered from Rust when embedding pico
     }
 
-    #[derive(Eq, Deserialize, Serialize, Debug, Hash, PartialEq)]
+    /// How requests whose trailing slash differs from the declared route are treated.
+    #[derive(Debug, Clone, Copy, PartialEq, Default)]
+    pub enum TrailingSlash {
+        /// `/login` and `/login/` must match the declared form exactly
+        Strict,
+        /// Mismatches are answered with a 308 to the declared form
+        Redirect,
+        /// `/login` and `/login/` resolve to the same route
+        #[default]
+        Ignore,
+    }
+
+    /// Global ROUTING settings, applied to both routes and static files.
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct RoutingConfig {
+        pub trailing_slash: TrailingSlash,
+        pub case_insensitive: bool,
+    }
+
+    impl RoutingConfig {
+        /// Returns the key used to look up a path segment in the route tree
+        pub fn segment_key(&self, segment: &str) -> String {
+            if self.case_insensitive {
+                segment.to_lowercase()
+            } else {
+                segment.to_string()
+            }
+        }
+    }
+
+    impl FromLua for TrailingSlash {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            let policy = match &value {
+                Value::String(s) => s.to_str().map(|s| s.to_uppercase()).ok(),
+                _ => None,
+            };
+            match policy.as_deref() {
+                Some("STRICT") => Ok(TrailingSlash::Strict),
+                Some("REDIRECT") => Ok(TrailingSlash::Redirect),
+                Some("IGNORE") => Ok(TrailingSlash::Ignore),
+                _ => Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "pico::route::TrailingSlash".to_string(),
+                    message: Some(
+                        "invalid TRAILING_SLASH policy, expected STRICT | REDIRECT | IGNORE"
+                            .to_string(),
+                    ),
+                }),
+            }
+        }
+    }
+
+    impl FromLua for RoutingConfig {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            match value {
+                Value::Table(t) => {
+                    let trailing_slash: Option<TrailingSlash> = t.get("TRAILING_SLASH")?;
+                    let case_insensitive: Option<bool> = t.get("CASE_INSENSITIVE")?;
+                    Ok(RoutingConfig {
+                        trailing_slash: trailing_slash.unwrap_or_default(),
+                        case_insensitive: case_insensitive.unwrap_or(false),
+                    })
+                }
+                _ => Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "pico::route::RoutingConfig".to_string(),
+                    message: Some("expected ROUTING to be a table".to_string()),
+                }),
+            }
+        }
+    }
+
+    #[derive(Eq, Deserialize, Serialize, Debug, Clone, Hash, PartialEq)]
     pub enum Method {
         GET,
         POST,
         PUT,
         DELETE,
+        OPTIONS,
         WS,
         SSE,
     }
@@ -38,6 +122,7 @@ pub mod route {
                 Method::POST => "POST",
                 Method::PUT => "PUT",
                 Method::DELETE => "DELETE",
+                Method::OPTIONS => "OPTIONS",
                 Method::WS => "WS",
                 Method::SSE => "SSE",
             };
@@ -53,6 +138,7 @@ pub mod route {
                 "post" => Method::POST,
                 "put" => Method::PUT,
                 "delete" => Method::DELETE,
+                "options" => Method::OPTIONS,
                 // TODO: not sure what ws or sse method is
                 "ws_upgrade?" => Method::WS,
                 "sse" => Method::SSE,
@@ -0,0 +1,356 @@
+pub mod sandbox {
+    use std::{
+        fmt,
+        time::{Duration, Instant},
+    };
+
+    use mlua::{FromLua, HookTriggers, Lua, Table, Value, VmState};
+
+    use crate::lua_duration;
+
+    /// Instructions run between checks of the handler deadline
+    const INSTRUCTIONS_PER_CHECK: u32 = 1000;
+
+    /// Parts of the Lua standard library handlers keep in the sandbox. Everything else in
+    /// the standard library is removed, globals defined by config.lua are kept.
+    const SAFE_GLOBALS: &[&str] = &[
+        "_G",
+        "_VERSION",
+        "assert",
+        "error",
+        "getmetatable",
+        "ipairs",
+        "next",
+        "pairs",
+        "pcall",
+        "print",
+        "rawequal",
+        "rawget",
+        "rawlen",
+        "rawset",
+        "select",
+        "setmetatable",
+        "tonumber",
+        "tostring",
+        "type",
+        "xpcall",
+        "coroutine",
+        "math",
+        "string",
+        "table",
+        "utf8",
+    ];
+    const SAFE_OS: &[&str] = &["clock", "date", "difftime", "getenv", "time"];
+
+    /// Environment variables handlers can't read in the sandbox, since they hold pico's
+    /// own secrets like PICO_SECRET_KEY and the database URL
+    const SECRET_ENV_PREFIXES: &[&str] = &["PICO_", "DATABASE_"];
+
+    /// Whether handlers in the sandbox may read an environment variable
+    fn readable_env_var(name: &str) -> bool {
+        let name = name.to_ascii_uppercase();
+        !SECRET_ENV_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    }
+
+    /// Replaces an environment variable getter, os.getenv or pico.env, with one returning
+    /// nil for secrets
+    fn filter_env_getter(table: &Table, name: &str, lua: &Lua) -> mlua::Result<()> {
+        if table.raw_get::<Value>(name)?.is_nil() {
+            return Ok(());
+        }
+        let getter = lua.create_function(|_, name: String| {
+            Ok(readable_env_var(&name)
+                .then(|| std::env::var(name).ok())
+                .flatten())
+        })?;
+        table.raw_set(name, getter)
+    }
+
+    /// Names of the standard library globals and os functions, read from a fresh Lua state
+    fn standard_library() -> mlua::Result<(Vec<String>, Vec<String>)> {
+        let lua = Lua::new();
+        let names = |table: Table| -> mlua::Result<Vec<String>> {
+            table
+                .pairs::<String, Value>()
+                .map(|pair| pair.map(|(name, _)| name))
+                .collect()
+        };
+        let os = names(lua.globals().get("os")?)?;
+        Ok((names(lua.globals())?, os))
+    }
+
+    /// Limits on the Lua handlers declared with SANDBOX
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct SandboxConfig {
+        pub timeout: Option<Duration>, // Time a single handler call can run for
+        pub memory_limit: Option<usize>, // Bytes the whole Lua state can allocate
+        pub allow: Vec<String>,        // Unsafe globals kept anyway, like 'io' or 'os.execute'
+    }
+
+    impl FromLua for SandboxConfig {
+        fn from_lua(value: Value, _lua: &Lua) -> mlua::Result<Self> {
+            let conversion_error = |message: String| mlua::Error::FromLuaConversionError {
+                from: "table",
+                to: "pico::sandbox::SandboxConfig".to_string(),
+                message: Some(message),
+            };
+            let t = match value {
+                Value::Boolean(true) => return Ok(SandboxConfig::default()),
+                Value::Table(t) => t,
+                _ => {
+                    return Err(conversion_error(
+                        "expected SANDBOX to be true or a table".to_string(),
+                    ));
+                }
+            };
+
+            let timeout = match t.get::<Value>("TIMEOUT")? {
+                Value::Nil => SandboxConfig::default().timeout,
+                Value::Boolean(false) => None,
+                v => Some(lua_duration(&v).filter(|d| !d.is_zero()).ok_or_else(|| {
+                    conversion_error(
+                        "invalid SANDBOX TIMEOUT, expected seconds, a duration like '500ms' or false"
+                            .to_string(),
+                    )
+                })?),
+            };
+            let memory_limit = match t.get::<Value>("MEMORY_LIMIT")? {
+                Value::Nil => SandboxConfig::default().memory_limit,
+                Value::Boolean(false) => None,
+                Value::Integer(bytes) if bytes > 0 => Some(bytes as usize),
+                Value::String(s) => Some(parse_size(&s.to_str()?).ok_or_else(|| {
+                    conversion_error(format!(
+                        "invalid SANDBOX MEMORY_LIMIT {}, expected bytes or a size like '64MB'",
+                        s.to_string_lossy()
+                    ))
+                })?),
+                _ => {
+                    return Err(conversion_error(
+                        "invalid SANDBOX MEMORY_LIMIT, expected bytes or a size like '64MB'"
+                            .to_string(),
+                    ));
+                }
+            };
+            let allow = t.get::<Option<Vec<String>>>("ALLOW")?.unwrap_or_default();
+            let (globals, os) = standard_library()?;
+            for name in &allow {
+                let known = match name.strip_prefix("os.") {
+                    Some(function) => os.iter().any(|f| f == function),
+                    None => globals.contains(name),
+                };
+                if !known {
+                    return Err(conversion_error(format!(
+                        "unknown SANDBOX ALLOW {}, expected a standard library global like 'io' or an os function like 'os.execute'",
+                        name
+                    )));
+                }
+            }
+
+            Ok(SandboxConfig {
+                timeout,
+                memory_limit,
+                allow,
+            })
+        }
+    }
+
+    impl Default for SandboxConfig {
+        fn default() -> Self {
+            SandboxConfig {
+                timeout: Some(Duration::from_secs(1)),
+                memory_limit: Some(64 * 1024 * 1024),
+                allow: vec![],
+            }
+        }
+    }
+
+    /// Parses sizes like '512KB', '64MB' or '1GB' into bytes
+    pub fn parse_size(size: &str) -> Option<usize> {
+        let size = size.trim().to_uppercase();
+        let split = size
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(size.len());
+        let (number, unit) = size.split_at(split);
+        let multiplier = match unit.trim() {
+            "" | "B" => 1,
+            "KB" => 1024,
+            "MB" => 1024 * 1024,
+            "GB" => 1024 * 1024 * 1024,
+            _ => return None,
+        };
+        number
+            .parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .and_then(|n| n.checked_mul(multiplier))
+    }
+
+    /// The error a handler is aborted with once it runs past SANDBOX.TIMEOUT
+    #[derive(Debug)]
+    pub struct HandlerTimeout(pub Duration);
+
+    impl fmt::Display for HandlerTimeout {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "handler ran for longer than {:?}", self.0)
+        }
+    }
+
+    impl std::error::Error for HandlerTimeout {}
+
+    /// Whether a Lua error comes from a handler running past its timeout
+    pub fn is_timeout(error: &mlua::Error) -> bool {
+        error.chain().any(|e| e.is::<HandlerTimeout>())
+    }
+
+    /// Whether a Lua error comes from the Lua state running out of memory
+    pub fn is_out_of_memory(error: &mlua::Error) -> bool {
+        matches!(error, mlua::Error::MemoryError(_))
+            || error
+                .chain()
+                .any(|e| matches!(e.downcast_ref(), Some(mlua::Error::MemoryError(_))))
+    }
+
+    /// The error aborting the running handler once it's past its deadline
+    pub fn handler_timeout(lua: &Lua) -> mlua::Error {
+        let timeout = match lua.app_data_ref::<Deadline>() {
+            Some(deadline) => deadline.timeout,
+            None => lua
+                .app_data_ref::<SandboxConfig>()
+                .and_then(|c| c.timeout)
+                .unwrap_or_default(),
+        };
+        mlua::Error::external(HandlerTimeout(timeout))
+    }
+
+    /// Deadline of the handler call currently running
+    struct Deadline {
+        at: Instant,
+        timeout: Duration, // What the deadline was set from, for the error message
+    }
+
+    /// Time the running handler has left, None outside handlers or without a TIMEOUT.
+    /// Blocking calls made for a handler use it, since the hook can't interrupt them.
+    pub fn time_remaining(lua: &Lua) -> Option<Duration> {
+        lua.app_data_ref::<Deadline>()
+            .map(|deadline| deadline.at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Starts the clock on a handler call. The deadline is cleared when the timer is dropped,
+    /// so Lua running outside handlers is never interrupted.
+    pub struct HandlerTimer<'a> {
+        lua: &'a Lua,
+        started: bool, // False when nested in a handler call that already has a deadline
+    }
+
+    impl<'a> HandlerTimer<'a> {
+        pub fn start(lua: &'a Lua) -> Self {
+            let timeout = lua.app_data_ref::<SandboxConfig>().and_then(|c| c.timeout);
+            HandlerTimer::start_with(lua, timeout)
+        }
+
+        /// Starts the clock with a timeout other than SANDBOX.TIMEOUT, like the TIMEOUT of
+        /// a cron job. The Lua state needs install_timeout_hook for it to be enforced.
+        pub fn start_with(lua: &'a Lua, timeout: Option<Duration>) -> Self {
+            let started = match timeout {
+                Some(timeout) if lua.app_data_ref::<Deadline>().is_none() => {
+                    lua.set_app_data(Deadline {
+                        at: Instant::now() + timeout,
+                        timeout,
+                    });
+                    true
+                }
+                _ => false,
+            };
+            HandlerTimer { lua, started }
+        }
+
+        /// Fails when the handler ran past its deadline, even if it caught the timeout
+        /// error with pcall and returned normally
+        pub fn check<T>(&self, result: mlua::Result<T>) -> mlua::Result<T> {
+            let expired = self.started
+                && self
+                    .lua
+                    .app_data_ref::<Deadline>()
+                    .is_some_and(|deadline| Instant::now() > deadline.at);
+            match result {
+                Ok(_) if expired => Err(handler_timeout(self.lua)),
+                result => result,
+            }
+        }
+    }
+
+    impl Drop for HandlerTimer<'_> {
+        fn drop(&mut self) {
+            if self.started {
+                self.lua.remove_app_data::<Deadline>();
+            }
+        }
+    }
+
+    /// Removes unsafe parts of the standard library from the Lua state and installs the
+    /// SANDBOX limits. Runs after config.lua is loaded, so the config itself can still use
+    /// the full standard library.
+    pub fn apply_sandbox(lua: &Lua, config: SandboxConfig) -> Result<(), String> {
+        let globals = lua.globals();
+        let allowed = |name: &str| config.allow.iter().any(|a| a == name);
+        let remove = |table: &Table, name: &str| {
+            table
+                .raw_set(name, Value::Nil)
+                .map_err(|e| format!("error removing {} from the sandbox: {}", name, e))
+        };
+
+        let (standard_globals, standard_os) = standard_library()
+            .map_err(|e| format!("error reading the Lua standard library: {}", e))?;
+        for name in &standard_globals {
+            if name != "os" && !SAFE_GLOBALS.contains(&name.as_str()) && !allowed(name) {
+                remove(&globals, name)?;
+            }
+        }
+        if !allowed("os")
+            && let Ok(os) = globals.get::<Table>("os")
+        {
+            for name in &standard_os {
+                if !SAFE_OS.contains(&name.as_str()) && !allowed(&format!("os.{}", name)) {
+                    remove(&os, name)?;
+                }
+            }
+        }
+
+        if !allowed("os")
+            && !allowed("os.getenv")
+            && let Ok(os) = globals.get::<Table>("os")
+        {
+            filter_env_getter(&os, "getenv", lua)
+                .map_err(|e| format!("error filtering os.getenv in the sandbox: {}", e))?;
+        }
+        if let Ok(pico) = globals.get::<Table>("pico") {
+            filter_env_getter(&pico, "env", lua)
+                .map_err(|e| format!("error filtering pico.env in the sandbox: {}", e))?;
+        }
+
+        if let Some(limit) = config.memory_limit {
+            lua.set_memory_limit(limit)
+                .map_err(|e| format!("error setting SANDBOX MEMORY_LIMIT: {}", e))?;
+        }
+        if config.timeout.is_some() {
+            install_timeout_hook(lua)
+                .map_err(|e| format!("error setting SANDBOX TIMEOUT: {}", e))?;
+        }
+        lua.set_app_data(config);
+        Ok(())
+    }
+
+    /// Aborts the Lua running past the deadline of its HandlerTimer
+    pub fn install_timeout_hook(lua: &Lua) -> mlua::Result<()> {
+        // Coroutines inherit the hook, so handlers can't dodge it by yielding
+        lua.set_global_hook(
+            HookTriggers::new().every_nth_instruction(INSTRUCTIONS_PER_CHECK),
+            |lua, _debug| match lua.app_data_ref::<Deadline>() {
+                Some(deadline) if Instant::now() > deadline.at => Err(handler_timeout(lua)),
+                _ => Ok(VmState::Continue),
+            },
+        )
+    }
+}
@@ -4,11 +4,19 @@ pub mod sql {
         error::Error,
         fs::{self, File},
         io::Read,
+        path::Path,
+        sync::Mutex,
+        time::Duration,
     };
 
+    use bytes::BytesMut;
     use chrono::{DateTime, NaiveDate, NaiveDateTime};
     use log::{debug, error, info, warn};
-    use postgres::{Client, NoTls, Row, types::ToSql};
+    use mlua::{FromLua, Lua};
+    use postgres::{
+        Client, GenericClient, NoTls, Row,
+        types::{Format, IsNull, ToSql, Type, to_sql_checked},
+    };
     use serde_json::{Value, json};
     use sqlparser::{
         ast::{CreateFunction, Statement},
@@ -23,17 +31,25 @@ pub mod sql {
     pub struct SQL {
         pub connection: Client,
         pub functions: HashMap<String, Function>,
+        request: Option<RequestTransaction>, // Transaction of the request being handled, once started
+        test_transaction: bool, // Requests run in savepoints of a transaction a test rolls back
+    }
+
+    struct RequestTransaction {
+        role: Option<String>, // RLS role switched to with SET LOCAL ROLE
+        failed: bool,         // A query failed, so the transaction can only be rolled back
     }
 
     pub struct Function {
         pub fn_call_statement: String, // SQL statement to execute a function with indexed parameters
         pub parameters: Vec<String>, // Parameter names in order of insertion in the fn_call_statement
+        statement: Mutex<Option<postgres::Statement>>, // fn_call_statement once prepared on the connection
     }
 
     impl Function {
-        pub fn execute(
+        pub fn execute<C: GenericClient>(
             &self,
-            client: &mut Client,
+            client: &mut C,
             input: HashMap<String, Value>,
         ) -> Result<Value, ResponseCode> {
             let mut ingestion_params = vec![];
@@ -43,78 +59,34 @@ pub mod sql {
                     None => return Err(ResponseCode::BadRequest),
                 }
             }
-            // 1. Convert Vec<Value> to Vec<Box<dyn ToSql + Sync>> via explicit matching
-            debug!("Converting parameters to SQL types. Input params: {:#?}", &ingestion_params);
-            let boxed_params: Vec<Box<dyn ToSql + Sync>> = ingestion_params.clone()
-            .into_iter() // Consume the Vec to take ownership of each Value
-            .enumerate()
-            .map(|(idx, v)| {
-                debug!("Converting parameter {} of type: {}", idx, match &v {
-                    Value::String(_) => "String",
-                    Value::Number(_) => "Number", 
-                    Value::Bool(_) => "Bool",
-                    Value::Null => "Null",
-                    Value::Array(_) => "Array",
-                    Value::Object(_) => "Object",
-                });
-                match v {
-                    // Handle String values (maps to TEXT/VARCHAR or DATE if parseable)
-                    Value::String(s) => {
-                        debug!("Converting string parameter {}: '{}'", idx, &s);
-                        
-                        // Try to parse as date first (YYYY-MM-DD format)
-                        if let Ok(date) = NaiveDate::parse_from_str(&s, "%Y-%m-%d") {
-                            debug!("String parameter {} parsed as date: {}", idx, date);
-                            Box::new(date) as Box<dyn ToSql + Sync>
-                        } else {
-                            debug!("String parameter {} kept as string", idx);
-                            Box::new(s) as Box<dyn ToSql + Sync>
-                        }
+            // 1. Prepare the call first, so each parameter is converted to the type Postgres
+            // expects for it. Query strings and forms can then pass numbers as text.
+            // The statement is kept, so later calls skip the round trip.
+            let cached = self.statement.lock().unwrap().clone();
+            let statement = match cached {
+                Some(s) => s,
+                None => match client.prepare(&self.fn_call_statement) {
+                    Ok(s) => {
+                        *self.statement.lock().unwrap() = Some(s.clone());
+                        s
                     }
-                    // Handle Number values (maps to INTEGER, BIGINT, NUMERIC)
-                    Value::Number(n) => {
-                        debug!("Converting number parameter {}: {}", idx, &n);
-                        if let Some(i) = n.as_i64() {
-                            // Try to fit in i32 first (PostgreSQL INT4), then fall back to i64 (BIGINT)
-                            if i >= i32::MIN as i64 && i <= i32::MAX as i64 {
-                                let i32_val = i as i32;
-                                debug!("Number {} converted to i32: {}", idx, i32_val);
-                                Box::new(i32_val) as Box<dyn ToSql + Sync>
-                            } else {
-                                debug!("Number {} converted to i64 (too large for i32): {}", idx, i);
-                                Box::new(i) as Box<dyn ToSql + Sync>
-                            }
-                        } else if let Some(f) = n.as_f64() {
-                            debug!("Number {} converted to f64: {}", idx, f);
-                            // Treat as a floating point number
-                            Box::new(f) as Box<dyn ToSql + Sync>
-                        } else {
-                            // Catch numbers that are too large for i64/f64 (should be rare)
-                            error!("Number {} is too large or complex for simple SQL type: {}", idx, n);
-                            panic!("JSON number too large or complex for simple SQL type.")
-                        }
-                    }
-                    // Handle Boolean values (maps to BOOLEAN/BOOL)
-                    Value::Bool(b) => {
-                        debug!("Converting boolean parameter {}: {}", idx, b);
-                        Box::new(b) as Box<dyn ToSql + Sync>
-                    }
-                    // Handle Null values (maps to SQL NULL)
-                    Value::Null => {
-                        debug!("Converting null parameter {}", idx);
-                        // Option<T> is how you send NULL, using a placeholder type like &str
-                        Box::new(None::<&str>) as Box<dyn ToSql + Sync>
+                    Err(e) => {
+                        error!("Error preparing SQL: {} error: {}", &self.fn_call_statement, e);
+                        return Err(ResponseCode::InternalError);
                     }
-                    // Handle Array and Object (maps to JSONB or fails for simple types)
-                    // If the column expects TEXT/INT/BOOL, these are invalid
-                    _ => {
-                        error!("Unsupported JSON type for parameter {}: {:?}", idx, v);
-                        // If you hit this, the parameter is likely invalid for a simple column
-                        panic!("Unsupported JSON type {:?} for simple database column. Arrays/Objects must be handled as JSONB.", v)
+                },
+            };
+            debug!("Converting parameters to SQL types. Input params: {:#?}", &ingestion_params);
+            let mut boxed_params: Vec<Box<dyn ToSql + Sync>> = vec![];
+            for ((name, value), ty) in self.parameters.iter().zip(&ingestion_params).zip(statement.params()) {
+                match bind_json(value, ty) {
+                    Some(p) => boxed_params.push(p),
+                    None => {
+                        debug!("Parameter {} can't be converted to {}: {}", name, ty, value);
+                        return Err(ResponseCode::BadRequest);
                     }
                 }
-            })
-            .collect();
+            }
 
             debug!("Successfully converted {} parameters to SQL types", boxed_params.len());
 
@@ -128,9 +100,8 @@ pub mod sql {
             // fn_call_statement looks like the following when we execute it here.
             // SELECT function_name($1, $2);
             debug!("Executing SQL: {} with {} parameters", &self.fn_call_statement, params_slice.len());
-            debug!("Parameter slice length: {}", params_slice.len());
             
-            let res = match client.query(&self.fn_call_statement, &params_slice) {
+            let res = match client.query(&statement, params_slice) {
                 Ok(r) => {
                     debug!("SQL execution successful, got {} rows", r.len());
                     r
@@ -145,6 +116,12 @@ pub mod sql {
                     error!("  Error type: {}", std::any::type_name_of_val(&e));
                     error!("  Error message: {}", e);
                     error!("  Error source: {:?}", e.source());
+                    // A function replaced since it was prepared needs a new statement
+                    *self.statement.lock().unwrap() = None;
+                    // Data exceptions, like text that isn't a valid uuid, are the client's
+                    if e.code().is_some_and(|code| code.code().starts_with("22")) {
+                        return Err(ResponseCode::BadRequest);
+                    }
                     return Err(ResponseCode::InternalError);
                 }
             };
@@ -165,18 +142,425 @@ pub mod sql {
         }
     }
 
-    pub fn initialize_sql_service(conn_str: &String) -> Result<SQL, String> {
-        let mut connection = match Client::connect(conn_str.as_str(), NoTls) {
+    /// RLS settings: when configured, every SQL function runs in a transaction that
+    /// first exposes the request's JWT claims to Postgres, PostgREST style.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct RlsConfig {
+        pub claims_setting: String, // Setting the claims JSON is stored in, read with current_setting()
+        pub role_claim: Option<String>, // Claim holding the Postgres role to SET LOCAL ROLE to
+        pub anon_role: Option<String>, // Role used for requests without a JWT
+    }
+
+    impl FromLua for RlsConfig {
+        fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
+            match value {
+                mlua::Value::Table(t) => {
+                    let claims_setting: Option<String> = t.get("CLAIMS_SETTING")?;
+                    let role_claim: Option<String> = t.get("ROLE_CLAIM")?;
+                    let anon_role: Option<String> = t.get("ANON_ROLE")?;
+                    // A token without the role claim would otherwise run as the connection's
+                    // user, which policies don't apply to
+                    if role_claim.is_some() && anon_role.is_none() {
+                        return Err(mlua::Error::FromLuaConversionError {
+                            from: "table",
+                            to: "pico::sql::RlsConfig".to_string(),
+                            message: Some("RLS with ROLE_CLAIM requires ANON_ROLE".to_string()),
+                        });
+                    }
+                    Ok(RlsConfig {
+                        claims_setting: claims_setting
+                            .unwrap_or("request.jwt.claims".to_string()),
+                        role_claim,
+                        anon_role,
+                    })
+                }
+                _ => Err(mlua::Error::FromLuaConversionError {
+                    from: value.type_name(),
+                    to: "pico::sql::RlsConfig".to_string(),
+                    message: Some("expected RLS to be a table".to_string()),
+                }),
+            }
+        }
+    }
+
+    impl SQL {
+        /// Starts the transaction the request's SQL runs in, unless it's already open. With
+        /// RLS configured it first exposes the JWT claims to Postgres and, when configured,
+        /// switches to the role derived from the claims with SET LOCAL.
+        pub fn begin_request(
+            &mut self,
+            rls: Option<&RlsConfig>,
+            claims: Option<&Value>,
+        ) -> Result<(), ResponseCode> {
+            if self.request.is_some() {
+                return Ok(());
+            }
+            let begin = match self.test_transaction {
+                true => "SAVEPOINT pico_request",
+                false => "BEGIN",
+            };
+            if let Err(e) = self.connection.batch_execute(begin) {
+                error!("Error starting request transaction: {}", e);
+                return Err(ResponseCode::InternalError);
+            }
+            self.request = Some(RequestTransaction {
+                role: None,
+                failed: false,
+            });
+            let rls = match rls {
+                Some(rls) => rls,
+                None => return Ok(()),
+            };
+
+            // set_config(..., true) is the parameterized form of SET LOCAL
+            let claims_json = claims.map(|c| c.to_string()).unwrap_or("{}".to_string());
+            if let Err(e) = self.connection.execute(
+                "SELECT set_config($1, $2, true)",
+                &[&rls.claims_setting, &claims_json],
+            ) {
+                error!("Error setting {} for RLS: {}", rls.claims_setting, e);
+                self.fail_request();
+                return Err(ResponseCode::InternalError);
+            }
+
+            let role = match (&rls.role_claim, claims) {
+                (Some(role_claim), Some(c)) => c
+                    .get(role_claim)
+                    .and_then(|r| r.as_str())
+                    .map(|r| r.to_string())
+                    .or(rls.anon_role.clone()),
+                _ => rls.anon_role.clone(),
+            };
+            // The config requires ANON_ROLE with ROLE_CLAIM, but an RlsConfig built in Rust
+            // may not have one. Running as the connection's user would bypass the policies.
+            if role.is_none() && rls.role_claim.is_some() {
+                error!("RLS has ROLE_CLAIM but no role for the request, set ANON_ROLE");
+                self.fail_request();
+                return Err(ResponseCode::Forbidden);
+            }
+            if let Some(role) = role {
+                let set_role = set_role_statement(&role);
+                debug!("Switching role for RLS: {}", set_role);
+                if let Err(e) = self.connection.batch_execute(&set_role) {
+                    error!("Error switching to role {} for RLS: {}", role, e);
+                    self.fail_request();
+                    return Err(ResponseCode::Forbidden);
+                }
+                if let Some(request) = &mut self.request {
+                    request.role = Some(role);
+                }
+            }
+            Ok(())
+        }
+
+        /// Marks the request's transaction as failed, so finish_request rolls it back
+        pub fn fail_request(&mut self) {
+            if let Some(request) = &mut self.request {
+                request.failed = true;
+            }
+        }
+
+        /// Starts a transaction for background work, like a cron run, and caps its queries to
+        /// the time the work has left. The cap is SET LOCAL, so it ends with the transaction.
+        pub fn limit_request(&mut self, remaining: Duration) -> Result<(), String> {
+            self.begin_request(None, None)
+                .map_err(|_| "error starting the transaction".to_string())?;
+            let timeout = format!("{}ms", remaining.as_millis().max(1));
+            match self.connection.execute("SELECT set_config('statement_timeout', $1, true)", &[&timeout]) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    self.fail_request();
+                    Err(format!("error setting the statement timeout: {}", e))
+                }
+            }
+        }
+
+        /// Ends the request's transaction, if one was started. It's committed when the
+        /// request succeeded, committing a transaction where a query failed is an error.
+        pub fn finish_request(&mut self, commit: bool) -> Result<(), String> {
+            let request = match self.request.take() {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            // In a test the savepoint is released instead, and the role it switched to with
+            // SET LOCAL would otherwise outlive it
+            let (commit_statement, rollback_statement) = match self.test_transaction {
+                true => (
+                    "RELEASE SAVEPOINT pico_request; RESET ROLE",
+                    "ROLLBACK TO SAVEPOINT pico_request; RELEASE SAVEPOINT pico_request",
+                ),
+                false => ("COMMIT", "ROLLBACK"),
+            };
+            if commit && !request.failed {
+                return self
+                    .connection
+                    .batch_execute(commit_statement)
+                    .map_err(|e| format!("error committing request transaction: {}", e));
+            }
+            self.connection
+                .batch_execute(rollback_statement)
+                .map_err(|e| format!("error rolling back request transaction: {}", e))?;
+            if commit {
+                return Err(
+                    "a query failed during the request, its transaction was rolled back"
+                        .to_string(),
+                );
+            }
+            Ok(())
+        }
+
+        /// Opens a transaction every later request runs in, as a savepoint, so a test can
+        /// roll back everything its requests wrote with rollback_test_transaction
+        pub fn begin_test_transaction(&mut self) -> Result<(), String> {
+            self.connection
+                .batch_execute("BEGIN")
+                .map_err(|e| format!("error starting test transaction: {}", e))?;
+            self.test_transaction = true;
+            Ok(())
+        }
+
+        pub fn rollback_test_transaction(&mut self) -> Result<(), String> {
+            if !self.test_transaction {
+                return Ok(());
+            }
+            self.test_transaction = false;
+            self.request = None;
+            self.connection
+                .batch_execute("ROLLBACK")
+                .map_err(|e| format!("error rolling back test transaction: {}", e))
+        }
+
+        /// Runs pico's own bookkeeping, like storing sessions, as the connecting user when
+        /// the request's transaction switched to an RLS role
+        pub fn as_owner<T>(&mut self, f: impl FnOnce(&mut Client) -> T) -> T {
+            let role = self.request.as_ref().and_then(|r| r.role.clone());
+            if role.is_some()
+                && let Err(e) = self.connection.batch_execute("SET LOCAL ROLE NONE")
+            {
+                error!("Error resetting RLS role: {}", e);
+            }
+            let result = f(&mut self.connection);
+            if let Some(role) = role
+                && let Err(e) = self.connection.batch_execute(&set_role_statement(&role))
+            {
+                error!("Error switching back to role {} for RLS: {}", role, e);
+            }
+            result
+        }
+
+        /// Runs a parameterized query for pico.sql.query and returns its rows. Each
+        /// parameter is bound to the type Postgres infers for its placeholder.
+        pub fn query_json(&mut self, text: &str, params: &[Value]) -> Result<Vec<Value>, String> {
+            let statement = match self.connection.prepare(text) {
+                Ok(statement) => statement,
+                Err(e) => {
+                    self.fail_request();
+                    return Err(e.to_string());
+                }
+            };
+            if statement.params().len() != params.len() {
+                return Err(format!(
+                    "query has {} parameters but {} were given",
+                    statement.params().len(),
+                    params.len()
+                ));
+            }
+            let boxed_params = statement
+                .params()
+                .iter()
+                .zip(params)
+                .enumerate()
+                .map(|(idx, (ty, value))| {
+                    bind_json(value, ty).ok_or_else(|| {
+                        format!(
+                            "can't bind {} to parameter ${} of type {}, cast it like ${}::text to pick another type",
+                            value,
+                            idx + 1,
+                            ty,
+                            idx + 1
+                        )
+                    })
+                })
+                .collect::<Result<Vec<Box<dyn ToSql + Sync>>, String>>()?;
+            let param_refs: Vec<&(dyn ToSql + Sync)> =
+                boxed_params.iter().map(|b| b.as_ref()).collect();
+
+            match self.connection.query(&statement, &param_refs) {
+                Ok(rows) => Ok(rows.iter().map(row_to_json).collect()),
+                Err(e) => {
+                    self.fail_request();
+                    Err(e.to_string())
+                }
+            }
+        }
+    }
+
+    /// Roles can't be bound as parameters, so quote them as an identifier
+    fn set_role_statement(role: &str) -> String {
+        format!("SET LOCAL ROLE \"{}\"", role.replace('"', "\"\""))
+    }
+
+    /// A parameter sent in Postgres' text format, so the server parses it into the
+    /// placeholder's type
+    #[derive(Debug)]
+    struct TextParam(String);
+
+    impl ToSql for TextParam {
+        fn to_sql(
+            &self,
+            _ty: &Type,
+            out: &mut BytesMut,
+        ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+            out.extend_from_slice(self.0.as_bytes());
+            Ok(IsNull::No)
+        }
+
+        fn accepts(_ty: &Type) -> bool {
+            true
+        }
+
+        fn encode_format(&self, _ty: &Type) -> Format {
+            Format::Text
+        }
+
+        to_sql_checked!();
+    }
+
+    /// Converts a JSON value for a placeholder of the given type. Null binds to any type,
+    /// strings from query strings and forms bind to numbers and booleans, and a single
+    /// value binds to an array of one. Scalars for other types, like uuid and numeric,
+    /// are sent as text for Postgres to parse.
+    fn bind_json(value: &Value, ty: &Type) -> Option<Box<dyn ToSql + Sync>> {
+        fn bind<T: ToSql + Sync + 'static>(
+            value: &Value,
+            convert: impl Fn(&Value) -> Option<T>,
+        ) -> Option<Box<dyn ToSql + Sync>> {
+            if value.is_null() {
+                return Some(Box::new(None::<T>));
+            }
+            convert(value).map(|v| Box::new(v) as Box<dyn ToSql + Sync>)
+        }
+        fn bind_array<T: ToSql + Sync + 'static>(
+            value: &Value,
+            convert: impl Fn(&Value) -> Option<T>,
+        ) -> Option<Box<dyn ToSql + Sync>> {
+            let element = |v: &Value| match v {
+                Value::Null => Some(None),
+                v => convert(v).map(Some),
+            };
+            let elements: Vec<Option<T>> = match value {
+                Value::Null => return Some(Box::new(None::<Vec<Option<T>>>)),
+                Value::Array(values) => values.iter().map(element).collect::<Option<_>>()?,
+                value => vec![element(value)?],
+            };
+            Some(Box::new(elements))
+        }
+
+        match ty.name() {
+            "bool" => bind(value, json_bool),
+            "int2" => bind(value, json_integer::<i16>),
+            "int4" => bind(value, json_integer::<i32>),
+            "int8" => bind(value, json_integer::<i64>),
+            "float4" => bind(value, |v| json_float(v).map(|f| f as f32)),
+            "float8" => bind(value, json_float),
+            "text" | "varchar" | "bpchar" | "name" => bind(value, json_text),
+            "json" | "jsonb" => bind(value, |v| Some(v.clone())),
+            "date" => bind(value, |v| {
+                v.as_str()
+                    .and_then(|s| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok())
+            }),
+            "timestamp" => bind(value, |v| {
+                v.as_str().and_then(|s| s.parse::<NaiveDateTime>().ok())
+            }),
+            "timestamptz" => bind(value, |v| {
+                v.as_str()
+                    .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+            }),
+            "_bool" => bind_array(value, json_bool),
+            "_int2" => bind_array(value, json_integer::<i16>),
+            "_int4" => bind_array(value, json_integer::<i32>),
+            "_int8" => bind_array(value, json_integer::<i64>),
+            "_float4" => bind_array(value, |v| json_float(v).map(|f| f as f32)),
+            "_float8" => bind_array(value, json_float),
+            "_text" | "_varchar" => bind_array(value, json_text),
+            _ => bind(value, |v| match v {
+                Value::Bool(b) => Some(TextParam(b.to_string())),
+                v => json_text(v).map(TextParam),
+            }),
+        }
+    }
+
+    fn json_bool(value: &Value) -> Option<bool> {
+        value
+            .as_bool()
+            .or_else(|| value.as_str()?.trim().parse().ok())
+    }
+
+    fn json_integer<T: TryFrom<i64>>(value: &Value) -> Option<T> {
+        value
+            .as_i64()
+            .or_else(|| value.as_str()?.trim().parse().ok())
+            .and_then(|i| T::try_from(i).ok())
+    }
+
+    fn json_float(value: &Value) -> Option<f64> {
+        value
+            .as_f64()
+            .or_else(|| value.as_str()?.trim().parse().ok())
+    }
+
+    fn json_text(value: &Value) -> Option<String> {
+        match value {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn initialize_sql_service(
+        conn_str: &str,
+        functions_dir: &Path,
+        migrations_dir: &Path,
+    ) -> Result<SQL, String> {
+        let connection = match Client::connect(conn_str, NoTls) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("error connecting to database, {}", e)),
+        };
+        initialize_sql_client(connection, functions_dir, migrations_dir)
+    }
+
+    /// Connects to a database pico was already set up on, loading the functions without
+    /// applying the migrations, for background threads that need a connection of their own
+    pub fn connect_sql_service(conn_str: &str, functions_dir: &Path) -> Result<SQL, String> {
+        let mut connection = match Client::connect(conn_str, NoTls) {
             Ok(c) => c,
             Err(e) => return Err(format!("error connecting to database, {}", e)),
         };
+        let functions = match load_functions(&mut connection, functions_dir, false) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("error loading sql functions: {}", e)),
+        };
+        Ok(SQL {
+            connection,
+            functions,
+            request: None,
+            test_transaction: false,
+        })
+    }
 
-        match migrate_db(&mut connection) {
+    /// Sets up pico on an open connection, applying the migrations and loading the
+    /// functions found in the given directories
+    pub fn initialize_sql_client(
+        mut connection: Client,
+        functions_dir: &Path,
+        migrations_dir: &Path,
+    ) -> Result<SQL, String> {
+        match migrate_db(&mut connection, migrations_dir) {
             Ok(_) => {}
             Err(e) => return Err(format!("error migrating database, {}", e)),
         }
 
-        let functions = match load_functions(&mut connection) {
+        let functions = match load_functions(&mut connection, functions_dir, true) {
             Ok(s) => s,
             Err(e) => return Err(format!("error loading sql functions: {}", e)),
         };
@@ -184,6 +568,8 @@ pub mod sql {
         return Ok(SQL {
             connection,
             functions,
+            request: None,
+            test_transaction: false,
         });
     }
 
@@ -192,12 +578,15 @@ pub mod sql {
     /// so that we make more complex apps.
     fn load_functions(
         client: &mut Client,
+        functions_dir: &Path,
+        create: bool, // Create or replace the functions in the database
     ) -> Result<HashMap<String, Function>, Box<dyn std::error::Error>> {
-        let dir_entries = match fs::read_dir("functions/") {
+        let dir_entries = match fs::read_dir(functions_dir) {
             Ok(e) => e,
             Err(e) => {
                 return Err(format!(
-                    "failed to read functions/ directory for stored sql scripts: {}",
+                    "failed to read {} directory for stored sql scripts: {}",
+                    functions_dir.display(),
                     e
                 )
                 .into());
@@ -256,26 +645,29 @@ pub mod sql {
                 // Use the file name as function name (already extracted above)
                 let function_name = &file_name;
 
-                // Drop the function if it exists (ignore errors if it doesn't exist)
-                let drop_sql = format!("DROP FUNCTION IF EXISTS {} CASCADE", function_name);
-                match client.execute(&drop_sql, &[]) {
-                    Ok(_) => info!("Dropped existing function: {}", function_name),
-                    Err(e) => debug!(
-                        "Note: Could not drop function {} (may not exist): {}",
-                        function_name, e
-                    ),
-                }
+                // Threads connecting to a database already set up only read the functions
+                if create {
+                    // Drop the function if it exists (ignore errors if it doesn't exist)
+                    let drop_sql = format!("DROP FUNCTION IF EXISTS {} CASCADE", function_name);
+                    match client.execute(&drop_sql, &[]) {
+                        Ok(_) => info!("Dropped existing function: {}", function_name),
+                        Err(e) => debug!(
+                            "Note: Could not drop function {} (may not exist): {}",
+                            function_name, e
+                        ),
+                    }
 
-                // Create the new function
-                match client.execute(&function.to_string(), &[]) {
-                    Ok(_) => info!("Created function: {}", function_name),
-                    Err(e) => {
-                        return Err(format!(
-                            "failed to create sql function {:#?}: error {}",
-                            entry.file_name().to_str(),
-                            e
-                        )
-                        .into());
+                    // Create the new function
+                    match client.execute(&function.to_string(), &[]) {
+                        Ok(_) => info!("Created function: {}", function_name),
+                        Err(e) => {
+                            return Err(format!(
+                                "failed to create sql function {:#?}: error {}",
+                                entry.file_name().to_str(),
+                                e
+                            )
+                            .into());
+                        }
                     }
                 }
 
@@ -310,6 +702,7 @@ pub mod sql {
                     Function {
                         fn_call_statement,
                         parameters,
+                        statement: Mutex::new(None),
                     },
                 );
             }
@@ -317,7 +710,10 @@ pub mod sql {
         Ok(functions)
     }
 
-    fn migrate_db(connection: &mut Client) -> Result<(), Box<dyn std::error::Error>> {
+    fn migrate_db(
+        connection: &mut Client,
+        migrations_dir: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         // Check for migrations table
         match connection.execute("CREATE SCHEMA IF NOT EXISTS pico", &[]) {
             Ok(_) => {}
@@ -341,11 +737,12 @@ pub mod sql {
             Ok(None) => 0, // default to epoch
             Err(e) => return Err(format!("db error while applying migrations: {}", e).into()),
         };
-        let dir_entries = match fs::read_dir("migrations/") {
+        let dir_entries = match fs::read_dir(migrations_dir) {
             Ok(des) => des,
             Err(e) => {
                 return Err(format!(
-                    "error finding migrations folder: {}\nIf using a custom directory to store migrations please define migrations = 'path/to/migrations/' in the DB table of your pico config",
+                    "error finding migrations folder {}: {}\nIf using a custom directory to store migrations please set it with PicoServiceBuilder::migrations_dir",
+                    migrations_dir.display(),
                     e
                 ).into());
             }
@@ -500,7 +897,7 @@ pub mod sql {
         }
     }
 
-    fn row_to_json(row: &Row) -> Value {
+    pub fn row_to_json(row: &Row) -> Value {
         let mut obj = serde_json::Map::new();
 
         for (idx, column) in row.columns().iter().enumerate() {
@@ -547,13 +944,20 @@ pub mod sql {
                         Value::Null
                     }
                 },
-                "text" | "varchar" | "char" => match row.try_get::<_, String>(idx) {
+                "text" | "varchar" | "char" | "bpchar" | "name" => match row.try_get::<_, String>(idx) {
                     Ok(v) => json!(v),
                     Err(e) => {
                         warn!("Error reading string column '{}': {:?}", column.name(), e);
                         Value::Null
                     }
                 },
+                "json" | "jsonb" => match row.try_get::<_, Value>(idx) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("Error reading json column '{}': {:?}", column.name(), e);
+                        Value::Null
+                    }
+                },
                 "uuid" => match row.try_get::<_, String>(idx) {
                     Ok(v) => json!(v), // UUID as string; safer for postgres 0.19.11
                     Err(e) => {
@@ -0,0 +1,643 @@
+pub mod testing {
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        fs,
+        net::{IpAddr, Ipv4Addr},
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    };
+
+    use mlua::{Function, Lua, LuaSerdeExt, Table};
+    use serde_json::Value;
+
+    use crate::{
+        PicoRequest, PicoService, PicoServiceBuilder,
+        http::http::{Body, PicoResponse, parse_query_parameters},
+        pico::pico::register_pico_module,
+        ratelimit::ratelimit::RateLimiter,
+        route::route::Method,
+    };
+
+    /// Environment variable naming the database tests run against
+    pub const TEST_DB_VAR: &str = "PICO_TEST_DB";
+
+    /// Secret signing the JWTs of tests when PICO_SECRET_KEY isn't set
+    const TEST_SECRET_KEY: &str = "pico-test-secret";
+
+    /// Sends requests straight to a PicoService, without a socket, keeping the cookies it
+    /// sets like a browser would. Everything the requests write to the database is rolled
+    /// back when the client is dropped.
+    pub struct TestClient {
+        service: PicoService,
+        cookies: Vec<(String, String)>,
+    }
+
+    impl TestClient {
+        /// Builds the service and opens the transaction every request runs in
+        pub fn new(builder: PicoServiceBuilder) -> Result<Self, String> {
+            TestClient::from_service(builder.build()?)
+        }
+
+        /// Builds the project in the current working directory against the test database,
+        /// read from PICO_TEST_DB, falling back to the config's DB
+        pub fn from_env() -> Result<Self, String> {
+            let mut builder = PicoServiceBuilder::new();
+            if let Ok(db) = std::env::var(TEST_DB_VAR) {
+                builder = builder.db(db);
+            }
+            if std::env::var("PICO_SECRET_KEY").is_err() {
+                builder = builder.secret_key(TEST_SECRET_KEY);
+            }
+            TestClient::new(builder)
+        }
+
+        pub fn from_service(mut service: PicoService) -> Result<Self, String> {
+            service.sql.begin_test_transaction()?;
+            Ok(TestClient {
+                service,
+                cookies: vec![],
+            })
+        }
+
+        pub fn get(&mut self, path: &str) -> TestResponse {
+            self.send(TestRequest::new(Method::GET, path))
+        }
+
+        pub fn post(&mut self, path: &str, body: Value) -> TestResponse {
+            self.send(TestRequest::new(Method::POST, path).json(body))
+        }
+
+        pub fn put(&mut self, path: &str, body: Value) -> TestResponse {
+            self.send(TestRequest::new(Method::PUT, path).json(body))
+        }
+
+        pub fn delete(&mut self, path: &str) -> TestResponse {
+            self.send(TestRequest::new(Method::DELETE, path))
+        }
+
+        /// Sends a request with the cookies collected so far, then stores the cookies the
+        /// response sets
+        pub fn send(&mut self, request: TestRequest) -> TestResponse {
+            let mut request = request.into_pico_request();
+            if !self.cookies.is_empty() && !request.headers.contains_key("cookie") {
+                let cookies: Vec<String> = self
+                    .cookies
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect();
+                request
+                    .headers
+                    .insert("cookie".to_string(), vec![cookies.join("; ")]);
+            }
+
+            let response = self.service.handle_http_pico_request(request);
+            for set_cookie in response.headers.get("Set-Cookie").into_iter().flatten() {
+                self.store_cookie(set_cookie);
+            }
+            TestResponse { response }
+        }
+
+        fn store_cookie(&mut self, set_cookie: &str) {
+            let mut parts = set_cookie.split(';').map(str::trim);
+            let (name, value) = match parts.next().and_then(|c| c.split_once('=')) {
+                Some(cookie) => cookie,
+                None => return,
+            };
+            let expired = parts.any(|attribute| attribute.eq_ignore_ascii_case("Max-Age=0"));
+            self.cookies.retain(|(n, _)| n != name);
+            if !expired && !value.is_empty() {
+                self.cookies.push((name.to_string(), value.to_string()));
+            }
+        }
+
+        /// A cookie the responses so far have set
+        pub fn cookie(&self, name: &str) -> Option<&str> {
+            self.cookies
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| value.as_str())
+        }
+
+        pub fn set_cookie(&mut self, name: &str, value: &str) {
+            self.cookies.retain(|(n, _)| n != name);
+            self.cookies.push((name.to_string(), value.to_string()));
+        }
+
+        pub fn clear_cookies(&mut self) {
+            self.cookies.clear();
+        }
+
+        /// Rolls back everything written since the client was created or last reset, and
+        /// forgets its cookies and in-memory rate limits, so the next test starts from a
+        /// clean slate
+        pub fn reset(&mut self) -> Result<(), String> {
+            self.service.sql.rollback_test_transaction()?;
+            self.service.sql.begin_test_transaction()?;
+            self.service.rate_limiter = RateLimiter::default();
+            self.cookies.clear();
+            Ok(())
+        }
+
+        /// Signs a JWT with the service's keys, for requests made as a given user
+        pub fn sign_jwt(&self, claims: Value) -> Result<String, String> {
+            self.service.jwt.encode(claims).map(|(token, _)| token)
+        }
+
+        /// Runs a query in the test's transaction, for fixtures and for checking what the
+        /// requests wrote
+        pub fn query(&mut self, text: &str, params: &[Value]) -> Result<Vec<Value>, String> {
+            self.service.sql.query_json(text, params)
+        }
+
+        /// Runs a script of SQL statements in the test's transaction, like a fixture file
+        pub fn execute(&mut self, sql: &str) -> Result<(), String> {
+            self.service
+                .sql
+                .connection
+                .batch_execute(sql)
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    impl Drop for TestClient {
+        fn drop(&mut self) {
+            if let Err(e) = self.service.sql.rollback_test_transaction() {
+                log::error!("{}", e);
+            }
+        }
+    }
+
+    /// A request for TestClient::send. The path can carry a query string.
+    pub struct TestRequest {
+        method: Method,
+        path: String,
+        headers: HashMap<String, Vec<String>>,
+        body: Body,
+    }
+
+    impl TestRequest {
+        pub fn new(method: Method, path: &str) -> Self {
+            TestRequest {
+                method,
+                path: path.to_string(),
+                headers: HashMap::new(),
+                body: Body::Json(Value::Null),
+            }
+        }
+
+        pub fn header(mut self, name: &str, value: &str) -> Self {
+            self.headers
+                .entry(name.to_lowercase())
+                .or_default()
+                .push(value.to_string());
+            self
+        }
+
+        pub fn bearer(self, token: &str) -> Self {
+            self.header("authorization", &format!("Bearer {}", token))
+        }
+
+        pub fn json(mut self, body: Value) -> Self {
+            self.body = Body::Json(body);
+            self
+        }
+
+        pub fn form(mut self, fields: &[(&str, &str)]) -> Self {
+            self.body = Body::Form(
+                fields
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            );
+            self
+        }
+
+        fn into_pico_request(self) -> PicoRequest {
+            let (path, raw_query) = match self.path.split_once('?') {
+                Some((path, query)) => (path.to_string(), query.to_string()),
+                None => (self.path, String::new()),
+            };
+            PicoRequest {
+                method: self.method,
+                path,
+                query: parse_query_parameters(&raw_query),
+                raw_query,
+                version: "HTTP/1.1".to_string(),
+                headers: self.headers,
+                body: self.body,
+                remote_addr: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            }
+        }
+    }
+
+    /// The response to a TestRequest. The assert helpers panic with the response body, so
+    /// failed tests show what pico answered.
+    pub struct TestResponse {
+        pub response: PicoResponse,
+    }
+
+    impl TestResponse {
+        pub fn status(&self) -> u16 {
+            self.response.status.to_code()
+        }
+
+        pub fn text(&self) -> String {
+            String::from_utf8_lossy(&self.response.body).to_string()
+        }
+
+        /// The body parsed as JSON, null for an empty or non JSON body
+        pub fn json(&self) -> Value {
+            serde_json::from_slice(&self.response.body).unwrap_or_default()
+        }
+
+        /// First value of a response header, matched case-insensitively
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.response
+                .headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .and_then(|(_, values)| values.first())
+                .map(|value| value.as_str())
+        }
+
+        pub fn assert_status(&self, status: u16) -> &Self {
+            assert_eq!(
+                self.status(),
+                status,
+                "unexpected status, response body: {}",
+                self.text()
+            );
+            self
+        }
+
+        pub fn assert_json(&self, expected: Value) -> &Self {
+            assert_eq!(self.json(), expected, "unexpected response body");
+            self
+        }
+
+        pub fn assert_header(&self, name: &str, value: &str) -> &Self {
+            assert_eq!(
+                self.header(name),
+                Some(value),
+                "unexpected {} header, response body: {}",
+                name,
+                self.text()
+            );
+            self
+        }
+    }
+
+    /// Outcome of one test from a tests/*.lua file
+    pub struct LuaTestResult {
+        pub file: String,
+        pub name: String,
+        pub error: Option<String>, // Why the test failed, None when it passed
+        pub duration: Duration,
+    }
+
+    /// The .lua files of a tests directory, in name order
+    pub fn find_lua_tests(dir: &Path) -> Result<Vec<PathBuf>, String> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => return Err(format!("failed to read {}: {}", dir.display(), e)),
+        };
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "lua"))
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /// Runs the tests of each file, in name order. A test file returns a table of test
+    /// functions keyed by their name, which drive the app through pico.test. Every test
+    /// runs in a transaction of its own, rolled back once it's done.
+    pub fn run_lua_tests(
+        client: &mut TestClient,
+        files: &[PathBuf],
+    ) -> Result<Vec<LuaTestResult>, String> {
+        let lua = Lua::new();
+        let module = create_test_module(&lua)
+            .map_err(|e| format!("error registering pico.test Lua module: {}", e))?;
+        let mut results = vec![];
+        let client = RefCell::new(client);
+        for file in files {
+            let file_name = file.display().to_string();
+            let failed_file = |error: String| LuaTestResult {
+                file: file_name.clone(),
+                name: "load".to_string(),
+                error: Some(error),
+                duration: Duration::ZERO,
+            };
+            let tests = fs::read_to_string(file)
+                .map_err(|e| e.to_string())
+                .and_then(|source| {
+                    lua.load(source)
+                        .set_name(format!("@{}", file_name))
+                        .eval::<Table>()
+                        .map_err(|e| lua_test_error(&e))
+                })
+                .and_then(|tests| {
+                    tests
+                        .pairs::<String, Function>()
+                        .collect::<mlua::Result<Vec<(String, Function)>>>()
+                        .map_err(|e| format!("expected a table of test functions: {}", e))
+                });
+            let mut tests = match tests {
+                Ok(tests) => tests,
+                Err(e) => {
+                    results.push(failed_file(e));
+                    continue;
+                }
+            };
+            tests.sort_by(|a, b| a.0.cmp(&b.0));
+
+            let fixtures = file.parent().unwrap_or(Path::new(".")).join("fixtures");
+            for (name, test) in tests {
+                let started = Instant::now();
+                let reset = client.borrow_mut().reset();
+                let error = match reset {
+                    Ok(()) => run_lua_test(&lua, &module, &client, &fixtures, test).err(),
+                    Err(e) => Some(e),
+                };
+                results.push(LuaTestResult {
+                    file: file_name.clone(),
+                    name,
+                    error,
+                    duration: started.elapsed(),
+                });
+            }
+        }
+        client.borrow_mut().reset()?;
+        Ok(results)
+    }
+
+    /// Registers the pico module with an empty pico.test, which run_lua_test binds to the
+    /// client while a test runs. Test files can keep a reference to it when they load.
+    fn create_test_module(lua: &Lua) -> mlua::Result<Table> {
+        register_pico_module(lua)?;
+        let module = lua.create_table()?;
+        module.set(
+            "equal",
+            lua.create_function(
+                |lua, (actual, expected, message): (mlua::Value, mlua::Value, Option<String>)| {
+                    let actual: Value = lua.from_value(actual)?;
+                    let expected: Value = lua.from_value(expected)?;
+                    if actual == expected {
+                        return Ok(());
+                    }
+                    // Blame the line of the test calling equal
+                    let line = lua
+                        .inspect_stack(1, |debug| {
+                            format!(
+                                "{}:{}: ",
+                                debug.source().short_src.unwrap_or_default(),
+                                debug.current_line().unwrap_or_default()
+                            )
+                        })
+                        .unwrap_or_default();
+                    Err(mlua::Error::runtime(format!(
+                        "{}{}expected {} but got {}",
+                        line,
+                        message.map(|m| format!("{}: ", m)).unwrap_or_default(),
+                        expected,
+                        actual
+                    )))
+                },
+            )?,
+        )?;
+        let pico: Table = lua.globals().get("pico")?;
+        pico.set("test", &module)?;
+        Ok(module)
+    }
+
+    /// Calls a test function with the requests and queries of pico.test bound to the client
+    fn run_lua_test(
+        lua: &Lua,
+        module: &Table,
+        client: &RefCell<&mut TestClient>,
+        fixtures: &Path,
+        test: Function,
+    ) -> Result<(), String> {
+        lua.scope(|scope| {
+            let send = |lua: &Lua, request: TestRequest, opts: &Option<Table>| {
+                let mut client = client.borrow_mut();
+                let mut request = request;
+                if let Some(opts) = opts {
+                    if let Some(headers) = opts.get::<Option<HashMap<String, String>>>("headers")? {
+                        for (name, value) in headers {
+                            request = request.header(&name, &value);
+                        }
+                    }
+                    if let Some(claims) = opts.get::<Option<mlua::Value>>("jwt")? {
+                        let token = client
+                            .sign_jwt(lua.from_value(claims)?)
+                            .map_err(mlua::Error::external)?;
+                        request = request.bearer(&token);
+                    }
+                    if let Some(form) = opts.get::<Option<HashMap<String, String>>>("form")? {
+                        let fields: Vec<(&str, &str)> =
+                            form.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+                        request = request.form(&fields);
+                    }
+                    if let Some(body) = opts.get::<Option<mlua::Value>>("body")? {
+                        request = request.json(lua.from_value(body)?);
+                    }
+                }
+                response_table(lua, &client.send(request))
+            };
+
+            module.set(
+                "request",
+                scope.create_function(
+                    move |lua, (method, path, opts): (String, String, Option<Table>)| {
+                        let method: Method = method.parse().map_err(|_| {
+                            mlua::Error::runtime(format!("unknown method {}", method))
+                        })?;
+                        send(lua, TestRequest::new(method, &path), &opts)
+                    },
+                )?,
+            )?;
+            module.set(
+                "get",
+                scope.create_function(move |lua, (path, opts): (String, Option<Table>)| {
+                    send(lua, TestRequest::new(Method::GET, &path), &opts)
+                })?,
+            )?;
+            module.set(
+                "delete",
+                scope.create_function(move |lua, (path, opts): (String, Option<Table>)| {
+                    send(lua, TestRequest::new(Method::DELETE, &path), &opts)
+                })?,
+            )?;
+            for (name, method) in [("post", Method::POST), ("put", Method::PUT)] {
+                module.set(
+                    name,
+                    scope.create_function(
+                        move |lua, (path, body, opts): (String, mlua::Value, Option<Table>)| {
+                            let request =
+                                TestRequest::new(method.clone(), &path).json(lua.from_value(body)?);
+                            send(lua, request, &opts)
+                        },
+                    )?,
+                )?;
+            }
+            module.set(
+                "sql",
+                scope.create_function(|lua, (text, params): (String, Option<mlua::Value>)| {
+                    let params: Vec<Value> = match params {
+                        Some(params) => lua.from_value(params)?,
+                        None => vec![],
+                    };
+                    let rows = client
+                        .borrow_mut()
+                        .query(&text, &params)
+                        .map_err(|e| mlua::Error::runtime(format!("pico.test.sql: {}", e)))?;
+                    lua.to_value(&rows)
+                })?,
+            )?;
+            module.set(
+                "fixture",
+                scope.create_function(|_, name: String| {
+                    let path = fixtures.join(format!("{}.sql", name));
+                    let sql = fs::read_to_string(&path).map_err(|e| {
+                        mlua::Error::runtime(format!(
+                            "pico.test.fixture: failed to read {}: {}",
+                            path.display(),
+                            e
+                        ))
+                    })?;
+                    client.borrow_mut().execute(&sql).map_err(|e| {
+                        mlua::Error::runtime(format!("pico.test.fixture: {}: {}", name, e))
+                    })
+                })?,
+            )?;
+            module.set(
+                "cookie",
+                scope.create_function(|_, name: String| {
+                    Ok(client.borrow().cookie(&name).map(|value| value.to_string()))
+                })?,
+            )?;
+            module.set(
+                "clear_cookies",
+                scope.create_function(|_, ()| {
+                    client.borrow_mut().clear_cookies();
+                    Ok(())
+                })?,
+            )?;
+            test.call::<()>(())
+        })
+        .map_err(|e| lua_test_error(&e))
+    }
+
+    /// The table a pico.test request returns to the test
+    fn response_table(lua: &Lua, response: &TestResponse) -> mlua::Result<Table> {
+        let table = lua.create_table()?;
+        table.set("status", response.status())?;
+        let headers = lua.create_table()?;
+        for (name, values) in &response.response.headers {
+            headers.set(name.to_lowercase(), values.join(", "))?;
+        }
+        table.set("headers", headers)?;
+        table.set("body", response.text())?;
+        let json = response.json();
+        if !json.is_null() {
+            table.set("json", lua.to_value(&json)?)?;
+        }
+        Ok(table)
+    }
+
+    /// The message of a failed test, without the Lua stack traceback
+    fn lua_test_error(error: &mlua::Error) -> String {
+        let message = match error {
+            mlua::Error::CallbackError { cause, .. } => return lua_test_error(cause),
+            mlua::Error::RuntimeError(message) => message.clone(),
+            error => error.to_string(),
+        };
+        message
+            .split("stack traceback:")
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    }
+
+    /// Formats test results in the Test Anything Protocol
+    pub fn tap_report(results: &[LuaTestResult]) -> String {
+        let mut report = format!("TAP version 13\n1..{}\n", results.len());
+        for (i, result) in results.iter().enumerate() {
+            let description = format!("{}: {}", result.file, result.name).replace('#', "\\#");
+            match &result.error {
+                None => report.push_str(&format!("ok {} - {}\n", i + 1, description)),
+                Some(error) => {
+                    report.push_str(&format!("not ok {} - {}\n", i + 1, description));
+                    report.push_str("  ---\n  message: |\n");
+                    for line in error.lines() {
+                        report.push_str(&format!("    {}\n", line));
+                    }
+                    report.push_str("  ...\n");
+                }
+            }
+        }
+        report
+    }
+
+    /// Formats test results as JUnit XML, one testsuite per file
+    pub fn junit_report(results: &[LuaTestResult]) -> String {
+        let failures =
+            |results: &[&LuaTestResult]| results.iter().filter(|r| r.error.is_some()).count();
+        let seconds = |results: &[&LuaTestResult]| {
+            results
+                .iter()
+                .map(|r| r.duration.as_secs_f64())
+                .sum::<f64>()
+        };
+        let all: Vec<&LuaTestResult> = results.iter().collect();
+        let mut report = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+            all.len(),
+            failures(&all),
+            seconds(&all)
+        );
+
+        let mut files: Vec<&str> = results.iter().map(|r| r.file.as_str()).collect();
+        files.dedup();
+        for file in files {
+            let suite: Vec<&LuaTestResult> = results.iter().filter(|r| r.file == file).collect();
+            report.push_str(&format!(
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+                xml_escape(file),
+                suite.len(),
+                failures(&suite),
+                seconds(&suite)
+            ));
+            for result in suite {
+                report.push_str(&format!(
+                    "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                    xml_escape(file),
+                    xml_escape(&result.name),
+                    result.duration.as_secs_f64()
+                ));
+                match &result.error {
+                    None => report.push_str("/>\n"),
+                    Some(error) => report.push_str(&format!(
+                        ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                        xml_escape(error.lines().next().unwrap_or_default()),
+                        xml_escape(error)
+                    )),
+                }
+            }
+            report.push_str("  </testsuite>\n");
+        }
+        report.push_str("</testsuites>\n");
+        report
+    }
+
+    fn xml_escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+}
@@ -0,0 +1,223 @@
+---@meta
+-- Type definitions for the pico global available in config.lua and Lua handlers.
+-- Generated by `picos init` so editors using lua-language-server can autocomplete it.
+
+---@class pico
+pico = {}
+
+---Returns a random version 4 UUID, like "5f0b6a8e-3d2c-4b7a-9e1f-2a6c8d4e0b13".
+---@return string
+function pico.uuid() end
+
+---Returns the current time in UTC as an RFC 3339 string, like "2025-01-31T09:30:00.123456+00:00".
+---@return string
+function pico.now() end
+
+---Returns the value of an environment variable, or nil when it is not set.
+---@param name string
+---@return string?
+function pico.env(name) end
+
+---@class pico.json
+pico.json = {}
+
+---Encodes a Lua value as a JSON string.
+---@param value any
+---@return string
+function pico.json.encode(value) end
+
+---Decodes a JSON string into a Lua value. Raises an error for invalid JSON.
+---@param text string
+---@return any
+function pico.json.decode(text) end
+
+---Stands for JSON null, and SQL NULL in pico.sql parameters.
+---@type lightuserdata
+pico.json.null = nil
+
+---Writes to the pico server log. Arguments are joined with spaces, like print.
+---@class pico.log
+pico.log = {}
+
+---@param ... any
+function pico.log.info(...) end
+
+---@param ... any
+function pico.log.warn(...) end
+
+---@param ... any
+function pico.log.error(...) end
+
+---@class pico.crypto
+pico.crypto = {}
+
+---Returns the SHA-256 digest of data as lowercase hex.
+---@param data string
+---@return string
+function pico.crypto.sha256(data) end
+
+---Returns the HMAC-SHA256 of data signed with key, as lowercase hex.
+---@param key string
+---@param data string
+---@return string
+function pico.crypto.hmac(key, data) end
+
+---Returns length cryptographically secure random bytes as a binary string.
+---Encode them with pico.base64.encode to use them as text.
+---@param length integer
+---@return string
+function pico.crypto.random_bytes(length) end
+
+---@class pico.base64
+pico.base64 = {}
+
+---Encodes data with the standard base64 alphabet.
+---@param data string
+---@return string
+function pico.base64.encode(data) end
+
+---Decodes standard base64. Raises an error for invalid input.
+---@param text string
+---@return string
+function pico.base64.decode(text) end
+
+---@class pico.url
+pico.url = {}
+
+---Percent-encodes text for use in a URL path segment or query parameter.
+---@param text string
+---@return string
+function pico.url.encode(text) end
+
+---Decodes percent-encoded text.
+---@param text string
+---@return string
+function pico.url.decode(text) end
+
+---Runs SQL in the request's transaction. Only available while handling a request.
+---@class pico.sql
+pico.sql = {}
+
+---Calls a function from functions/ with named parameters. Returns nil for no rows,
+---a table for one row and a list of tables for several.
+---@param name string
+---@param params? table<string, any>
+---@return table?
+function pico.sql.call(name, params) end
+
+---Runs a query with $1, $2, ... placeholders bound from params and returns its rows.
+---Needs LUA = { SQL_QUERY = true } in config.lua.
+---@param text string
+---@param params? any[]
+---@return table[]
+function pico.sql.query(text, params) end
+
+---@class pico.enqueue.options
+---@field run_at? number|string A delay in seconds or like '10m', or a timestamp
+---@field max_attempts? integer Replaces the job's MAX_ATTEMPTS
+
+---Enqueues a job declared in JOBS, in the request's transaction, and returns its id.
+---Only available while handling a request.
+---@param name string
+---@param payload? table
+---@param options? pico.enqueue.options
+---@return integer
+function pico.enqueue(name, payload, options) end
+
+---@class pico.http
+pico.http = {}
+
+---@class pico.http.options
+---@field url string An http or https URL on one of LUA.HTTP.HOSTS
+---@field method? string Defaults to GET
+---@field headers? table<string, string>
+---@field body? string|table Tables are sent as JSON
+---@field timeout? number|string Seconds or a duration like '5s', defaults to LUA.HTTP.TIMEOUT
+
+---@class pico.http.response
+---@field status integer
+---@field headers table<string, string> Header names are lowercase
+---@field body any Decoded for JSON responses, a string otherwise
+
+---Sends an HTTP request to a host listed in LUA.HTTP.HOSTS. Redirects are not followed.
+---@param options pico.http.options
+---@return pico.http.response
+function pico.http.request(options) end
+
+---The third argument of PREPROCESS and BEFORE, describing the request.
+---@class pico.ctx
+---@field method string Like "POST"
+---@field path string Like "/users/42"
+---@field headers table<string, string> Header names are lowercase, repeated headers are joined with ", "
+---@field cookies table<string, string>
+---@field query table<string, string>
+---@field raw_query string The query string without the "?"
+---@field params table<string, string> Route parameters, like user_id for users/:user_id
+---@field ip string? The client's IP address
+
+---Drives the app from a test file in tests/. Only available under `picos test`.
+---@class pico.test
+pico.test = {}
+
+---@class pico.test.options
+---@field jwt? table Claims signed into a JWT sent as a Bearer token
+---@field headers? table<string, string>
+---@field form? table<string, string> Sent as a form body
+---@field body? any Sent as a JSON body
+
+---@class pico.test.response
+---@field status integer
+---@field headers table<string, string> Header names are lowercase
+---@field body string
+---@field json any Decoded JSON body, nil for other responses
+
+---@param method string
+---@param path string Can carry a query string
+---@param options? pico.test.options
+---@return pico.test.response
+function pico.test.request(method, path, options) end
+
+---@param path string
+---@param options? pico.test.options
+---@return pico.test.response
+function pico.test.get(path, options) end
+
+---@param path string
+---@param body any Sent as JSON
+---@param options? pico.test.options
+---@return pico.test.response
+function pico.test.post(path, body, options) end
+
+---@param path string
+---@param body any Sent as JSON
+---@param options? pico.test.options
+---@return pico.test.response
+function pico.test.put(path, body, options) end
+
+---@param path string
+---@param options? pico.test.options
+---@return pico.test.response
+function pico.test.delete(path, options) end
+
+---Runs a query in the test's transaction, with $1, $2, ... bound from params.
+---@param text string
+---@param params? any[]
+---@return table[]
+function pico.test.sql(text, params) end
+
+---Runs tests/fixtures/<name>.sql in the test's transaction.
+---@param name string
+function pico.test.fixture(name) end
+
+---A cookie set by the responses of the test so far.
+---@param name string
+---@return string?
+function pico.test.cookie(name) end
+
+function pico.test.clear_cookies() end
+
+---Fails the test unless both values are equal, comparing tables by their contents.
+---@param actual any
+---@param expected any
+---@param message? string
+function pico.test.equal(actual, expected, message) end
@@ -0,0 +1,17 @@
+-- Tests run with `picos test`, against the database in PICO_TEST_DB. Each test runs in a
+-- transaction that is rolled back once it's done, so tests can't see each other's writes.
+local test = pico.test
+
+return {
+  ['ping answers pong'] = function()
+    local res = test.get '/ping'
+    test.equal(res.status, 200)
+    test.equal(res.json.message, 'pong')
+  end,
+
+  ['ping counts every request'] = function()
+    local first = test.get('/ping').json.count
+    local second = test.get('/ping').json.count
+    test.equal(second, first + 1, 'count after a second ping')
+  end,
+}